@@ -0,0 +1,534 @@
+/*
+
+ Script functions
+
+ Keywords can call FUNCTIONs defined in script, found by name or passed as parameter.
+ Script functions can also be handed to native libraries as extern "system" function pointers.
+
+*/
+
+use super::libloading;
+use super::{Engine, Ext, RunTimeError, TBStr, error_free, get_last_error, raise_runtime_error};
+
+// Main type codes as used by the engine for parameters and results
+const MAIN_TYPE_EXT: i32 = 10;
+const MAIN_TYPE_STRING: i32 = 30;
+
+// Parameter as passed to thinBasic_FunctionCall_ByPtr
+#[repr(C)]
+struct CallParam
+{
+    main_type: i32,
+    number: Ext,
+    _align: [u8; 2],
+    text: *const u8
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CallArg<'a>
+{
+    Number(f64),
+    Text(&'a str)
+}
+
+macro_rules! impl_call_arg_from_number {
+    ($($t:ty),*) => {
+        $(
+            impl<'a> From<$t> for CallArg<'a>
+            {
+                fn from(value: $t) -> Self {
+                    CallArg::Number(value as f64)
+                }
+            }
+        )*
+    }
+}
+
+impl_call_arg_from_number!(u8, i16, u16, u32, i32, i64, isize, usize, f32, f64);
+
+impl<'a> From<&'a str> for CallArg<'a>
+{
+    fn from(value: &'a str) -> Self {
+        CallArg::Text(value)
+    }
+}
+
+// Owned argument, for calls prepared away from the script thread
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallValue
+{
+    Number(f64),
+    Text(String)
+}
+
+impl CallValue
+{
+    pub fn as_arg(&self) -> CallArg<'_> {
+        match *self {
+            CallValue::Number(value) => CallArg::Number(value),
+            CallValue::Text(ref text) => CallArg::Text(text)
+        }
+    }
+}
+
+macro_rules! impl_call_value_from_number {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for CallValue
+            {
+                fn from(value: $t) -> Self {
+                    CallValue::Number(value as f64)
+                }
+            }
+        )*
+    }
+}
+
+impl_call_value_from_number!(u8, i16, u16, u32, i32, i64, isize, usize, f32, f64);
+
+impl From<String> for CallValue
+{
+    fn from(value: String) -> Self {
+        CallValue::Text(value)
+    }
+}
+
+impl<'a> From<&'a str> for CallValue
+{
+    fn from(value: &'a str) -> Self {
+        CallValue::Text(String::from(value))
+    }
+}
+
+// Rust types script function result can be converted to
+pub trait CallResult: Sized
+{
+    fn from_result(number: &Ext, text: &TBStr) -> Self;
+}
+
+macro_rules! impl_call_result_number {
+    ($($t:ty),*) => {
+        $(
+            impl CallResult for $t
+            {
+                fn from_result(number: &Ext, _text: &TBStr) -> Self {
+                    number.to_f64() as $t
+                }
+            }
+        )*
+    }
+}
+
+impl_call_result_number!(u8, i16, u16, u32, i32, i64, isize, usize, f32, f64);
+
+impl CallResult for String
+{
+    fn from_result(_number: &Ext, text: &TBStr) -> Self {
+        if text.0.is_null() { String::new() } else { text.to_string() }
+    }
+}
+
+impl CallResult for ()
+{
+    fn from_result(_number: &Ext, _text: &TBStr) -> Self {}
+}
+
+// FUNCTION defined in script
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptFunction
+{
+    function_ptr: usize
+}
+
+impl ScriptFunction
+{
+    pub fn name(&self, engine: Engine) -> Result<String, RunTimeError> {
+        unsafe {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_functiongetname: libloading::Symbol<unsafe extern "system" fn(function_ptr: usize, name: *mut TBStr)> = super::optional_export(engine, &lib, "thinBasic_FunctionGetName")?;
+
+            let mut name = TBStr(::std::ptr::null());
+            thinbasic_functiongetname(self.function_ptr, &mut name);
+
+            Ok(String::from_result(&Ext::default(), &name))
+        }
+    }
+
+    pub fn param_count(&self, engine: Engine) -> Result<usize, RunTimeError> {
+        unsafe {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_functiongetnumberofparams: libloading::Symbol<unsafe extern "system" fn(function_ptr: usize) -> i32> = super::optional_export(engine, &lib, "thinBasic_FunctionGetNumberOfParams")?;
+
+            Ok(thinbasic_functiongetnumberofparams(self.function_ptr).max(0) as usize)
+        }
+    }
+
+    // Runs the function, script runtime errors raised inside are returned as Err.
+    // Refused while VariableRef or ArrayRef exists, the script could free what they point to.
+    pub fn call<R: CallResult>(&self, engine: Engine, args: &[CallArg]) -> Result<R, RunTimeError> {
+        super::check_script_reentry(engine)?;
+
+        unsafe {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_functioncall_byptr: libloading::Symbol<unsafe extern "system" fn(function_ptr: usize, param_count: i32, params: *const CallParam, ext_result: *mut Ext, str_result: *mut TBStr) -> i32> = super::optional_export(engine, &lib, "thinBasic_FunctionCall_ByPtr")?;
+
+            // Strings must stay alive until the call returns
+            let texts: Vec<Option<TBStr>> = args.iter().map(|arg| match *arg {
+                CallArg::Text(text) => Some(TBStr::from(text)),
+                CallArg::Number(_) => None
+            }).collect();
+
+            let params: Vec<CallParam> = args.iter().zip(texts.iter()).map(|(arg, text)| match *arg {
+                CallArg::Number(value) => CallParam { main_type: MAIN_TYPE_EXT, number: Ext::from_f64(value), _align: [0; 2], text: ::std::ptr::null() },
+                CallArg::Text(_) => CallParam { main_type: MAIN_TYPE_STRING, number: Ext::default(), _align: [0; 2], text: text.as_ref().map_or(::std::ptr::null(), |text| text.0) }
+            }).collect();
+
+            let mut number = Ext::default();
+            let mut text = TBStr(::std::ptr::null());
+
+            thinbasic_functioncall_byptr(self.function_ptr, params.len() as i32, params.as_ptr(), &mut number, &mut text);
+
+            if !error_free(engine) {
+                return Err(get_last_error(engine));
+            }
+
+            Ok(R::from_result(&number, &text))
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub fn function_by_name(engine: Engine, function_name: &str) -> Result<ScriptFunction, RunTimeError>
+{
+    unsafe
+    {
+        let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+        let thinbasic_functiongetptr: libloading::Symbol<unsafe extern "system" fn(function_name: TBStr) -> usize> = super::optional_export(engine, &lib, "thinBasic_FunctionGetPtr")?;
+
+        match thinbasic_functiongetptr(TBStr::from(function_name)) {
+            0 => {
+                raise_runtime_error(engine, RunTimeError::FunctionNotFound, function_name);
+                Err(RunTimeError::FunctionNotFound)
+            }
+            function_ptr => Ok(ScriptFunction { function_ptr })
+        }
+    }
+}
+
+// Parses function passed as keyword argument, either by name or as function pointer
+#[allow(dead_code)]
+pub fn parse_function_ref(engine: Engine) -> Result<ScriptFunction, RunTimeError>
+{
+    unsafe
+    {
+        let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+        let thinbasic_functionparseandgetptr: libloading::Symbol<unsafe extern "system" fn(check_parens: i32) -> usize> = super::optional_export(engine, &lib, "thinBasic_FunctionParseAndGetPtr")?;
+
+        match thinbasic_functionparseandgetptr(0) {
+            0 => {
+                raise_runtime_error(engine, RunTimeError::FunctionExpectedCallback, "Script function expected as parameter");
+                Err(RunTimeError::FunctionExpectedCallback)
+            }
+            function_ptr => Ok(ScriptFunction { function_ptr })
+        }
+    }
+}
+
+/*
+
+ Native callbacks
+
+*/
+
+// Each signature gets this many distinct trampolines, all signatures share the slot table
+pub const NATIVE_CALLBACK_SLOTS: usize = 32;
+
+// Generation changes whenever the slot is cleared, so late drops of earlier callbacks leave it alone
+#[derive(Clone, Copy)]
+struct CallbackSlot
+{
+    function: Option<ScriptFunction>,
+    generation: u32,
+    // Pointer escaped with until_script_end, native code may still call it
+    escaped: bool,
+    // Escaped pointer outlived its script function, slot is never handed out again
+    retired: bool
+}
+
+impl CallbackSlot
+{
+    const EMPTY: CallbackSlot = CallbackSlot { function: None, generation: 0, escaped: false, retired: false };
+
+    fn is_free(&self) -> bool {
+        self.function.is_none() && !self.retired
+    }
+}
+
+static CALLBACK_SLOTS: ::std::sync::Mutex<[CallbackSlot; NATIVE_CALLBACK_SLOTS]> = ::std::sync::Mutex::new([CallbackSlot::EMPTY; NATIVE_CALLBACK_SLOTS]);
+
+// Takes free slot for function, returns the slot with its generation
+fn bind_slot(function: ScriptFunction) -> Option<(usize, u32)>
+{
+    let mut slots = CALLBACK_SLOTS.lock().ok()?;
+    let slot = slots.iter().position(CallbackSlot::is_free)?;
+
+    slots[slot].function = Some(function);
+    slots[slot].escaped = false;
+
+    Some((slot, slots[slot].generation))
+}
+
+// Clears slot only when it still belongs to the given generation
+fn unbind_slot(slot: usize, generation: u32)
+{
+    if let Ok(mut slots) = CALLBACK_SLOTS.lock() {
+        let entry = &mut slots[slot];
+        if entry.generation == generation && entry.function.is_some() {
+            entry.function = None;
+            entry.escaped = false;
+            entry.generation = entry.generation.wrapping_add(1);
+        }
+    }
+}
+
+// Function currently reachable through the slot, None for cleared and retired ones
+fn slot_function(slot: usize) -> Option<ScriptFunction>
+{
+    match CALLBACK_SLOTS.lock() {
+        Ok(slots) if !slots[slot].retired => slots[slot].function,
+        _ => None
+    }
+}
+
+// Native parameter types trampolines can forward to script
+pub trait NativeArg: Copy
+{
+    fn into_call_arg(self) -> CallArg<'static>;
+}
+
+macro_rules! impl_native_arg {
+    ($($t:ty),*) => {
+        $(
+            impl NativeArg for $t
+            {
+                fn into_call_arg(self) -> CallArg<'static> {
+                    CallArg::from(self)
+                }
+            }
+        )*
+    }
+}
+
+impl_native_arg!(u8, i16, u16, u32, i32, i64, isize, usize, f32, f64);
+
+// Pointers are passed to script as numbers, as thinBasic does with VARPTR
+impl<T> NativeArg for *const T
+{
+    fn into_call_arg(self) -> CallArg<'static> {
+        CallArg::Number(self as usize as f64)
+    }
+}
+
+impl<T> NativeArg for *mut T
+{
+    fn into_call_arg(self) -> CallArg<'static> {
+        CallArg::Number(self as usize as f64)
+    }
+}
+
+// Result returned to native caller, only types with C layout, fallback is used when the script function is gone or fails
+pub trait NativeResult: CallResult + Copy
+{
+    fn fallback() -> Self;
+}
+
+macro_rules! impl_native_result {
+    ($($t:ty),*) => {
+        $(
+            impl NativeResult for $t
+            {
+                fn fallback() -> Self {
+                    0 as $t
+                }
+            }
+        )*
+    }
+}
+
+impl_native_result!(u8, i16, u16, u32, i32, i64, isize, usize, f32, f64);
+
+// Function without result, void in C
+impl NativeResult for ()
+{
+    fn fallback() -> Self {}
+}
+
+// Script returns pointers as numbers, as it gets them from NativeArg
+impl<T> CallResult for *const T
+{
+    fn from_result(number: &Ext, _text: &TBStr) -> Self {
+        number.to_f64() as usize as *const T
+    }
+}
+
+impl<T> CallResult for *mut T
+{
+    fn from_result(number: &Ext, _text: &TBStr) -> Self {
+        number.to_f64() as usize as *mut T
+    }
+}
+
+impl<T> NativeResult for *const T
+{
+    fn fallback() -> Self {
+        ::std::ptr::null()
+    }
+}
+
+impl<T> NativeResult for *mut T
+{
+    fn fallback() -> Self {
+        ::std::ptr::null_mut()
+    }
+}
+
+// extern "system" function pointer types which can be backed by script function
+pub trait NativeSignature: Copy
+{
+    fn from_slot(slot: usize) -> Self;
+}
+
+macro_rules! trampoline_table {
+    ($trampoline:ident, $($ty:ident),*) => {
+        [$trampoline::<$($ty,)* 0>, $trampoline::<$($ty,)* 1>, $trampoline::<$($ty,)* 2>, $trampoline::<$($ty,)* 3>, $trampoline::<$($ty,)* 4>, $trampoline::<$($ty,)* 5>, $trampoline::<$($ty,)* 6>, $trampoline::<$($ty,)* 7>, $trampoline::<$($ty,)* 8>, $trampoline::<$($ty,)* 9>, $trampoline::<$($ty,)* 10>, $trampoline::<$($ty,)* 11>, $trampoline::<$($ty,)* 12>, $trampoline::<$($ty,)* 13>, $trampoline::<$($ty,)* 14>, $trampoline::<$($ty,)* 15>, $trampoline::<$($ty,)* 16>, $trampoline::<$($ty,)* 17>, $trampoline::<$($ty,)* 18>, $trampoline::<$($ty,)* 19>, $trampoline::<$($ty,)* 20>, $trampoline::<$($ty,)* 21>, $trampoline::<$($ty,)* 22>, $trampoline::<$($ty,)* 23>, $trampoline::<$($ty,)* 24>, $trampoline::<$($ty,)* 25>, $trampoline::<$($ty,)* 26>, $trampoline::<$($ty,)* 27>, $trampoline::<$($ty,)* 28>, $trampoline::<$($ty,)* 29>, $trampoline::<$($ty,)* 30>, $trampoline::<$($ty,)* 31>]
+    }
+}
+
+macro_rules! native_signature {
+    ($trampoline:ident; $($arg:ident: $ty:ident),*) => {
+        extern "system" fn $trampoline<$($ty: NativeArg,)* R: NativeResult, const SLOT: usize>($($arg: $ty),*) -> R
+        {
+            // Native libraries calling back from their own threads must not reach the engine
+            let engine = match Engine::current() {
+                Some(engine) => engine,
+                None => return R::fallback()
+            };
+
+            // Copied out, script function may create or release callbacks itself
+            match slot_function(SLOT) {
+                Some(function) => function.call::<R>(engine, &[$($arg.into_call_arg()),*]).unwrap_or_else(|_| R::fallback()),
+                None => R::fallback()
+            }
+        }
+
+        impl<$($ty: NativeArg,)* R: NativeResult> NativeSignature for extern "system" fn($($ty),*) -> R
+        {
+            fn from_slot(slot: usize) -> Self {
+                let table: [Self; NATIVE_CALLBACK_SLOTS] = trampoline_table!($trampoline, $($ty,)* R);
+                table[slot]
+            }
+        }
+    }
+}
+
+native_signature!(trampoline0; );
+native_signature!(trampoline1; a: A);
+native_signature!(trampoline2; a: A, b: B);
+native_signature!(trampoline3; a: A, b: B, c: C);
+native_signature!(trampoline4; a: A, b: B, c: C, d: D);
+native_signature!(trampoline5; a: A, b: B, c: C, d: D, e: E);
+
+// Script function bound to native function pointer, the pointer is valid until this is dropped
+pub struct NativeCallback<F: NativeSignature>
+{
+    slot: usize,
+    generation: u32,
+    function_ptr: F
+}
+
+impl<F: NativeSignature> NativeCallback<F>
+{
+    pub fn as_ptr(&self) -> F {
+        self.function_ptr
+    }
+
+    // Keeps the pointer valid until release_native_callbacks, for libraries which call back any time later
+    pub fn until_script_end(self) -> F {
+        let function_ptr = self.function_ptr;
+        if let Ok(mut slots) = CALLBACK_SLOTS.lock() {
+            if slots[self.slot].generation == self.generation {
+                slots[self.slot].escaped = true;
+            }
+        }
+        ::std::mem::forget(self);
+
+        function_ptr
+    }
+}
+
+impl<F: NativeSignature> Drop for NativeCallback<F>
+{
+    fn drop(&mut self) {
+        unbind_slot(self.slot, self.generation);
+    }
+}
+
+impl ScriptFunction
+{
+    // Calls from other threads than the script one return fallback value without running the script function
+    pub fn native_callback<F: NativeSignature>(&self, engine: Engine) -> Result<NativeCallback<F>, RunTimeError> {
+        match bind_slot(*self) {
+            Some((slot, generation)) => Ok(NativeCallback { slot, generation, function_ptr: F::from_slot(slot) }),
+            None => {
+                raise_runtime_error(engine, RunTimeError::ModuleSpecific, "Too many native callbacks in use");
+                Err(RunTimeError::ModuleSpecific)
+            }
+        }
+    }
+}
+
+// Detaches all script functions from native pointers, they return fallback values from now on
+// Slots whose pointer escaped with until_script_end are retired, so a later callback never answers a stale pointer
+#[allow(dead_code)]
+pub fn release_native_callbacks()
+{
+    if let Ok(mut slots) = CALLBACK_SLOTS.lock() {
+        for slot in slots.iter_mut() {
+            if slot.function.is_some() {
+                slot.retired |= slot.escaped;
+                slot.function = None;
+                slot.escaped = false;
+                slot.generation = slot.generation.wrapping_add(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind_slot, release_native_callbacks, slot_function, unbind_slot, ScriptFunction, CALLBACK_SLOTS};
+
+    #[test]
+    fn stale_callbacks_never_reach_reused_slots() {
+        let first = ScriptFunction { function_ptr: 1 };
+        let second = ScriptFunction { function_ptr: 2 };
+
+        // Dropped after release, must not clear the callback which took its slot since
+        let (slot, generation) = bind_slot(first).unwrap();
+        release_native_callbacks();
+        let (reused, reused_generation) = bind_slot(second).unwrap();
+        assert_eq!(reused, slot);
+        unbind_slot(slot, generation);
+        assert_eq!(slot_function(slot).map(|function| function.function_ptr), Some(2));
+
+        // Escaped pointer keeps its slot retired after release
+        // Same marking as until_script_end, trampolines cannot be linked into tests
+        CALLBACK_SLOTS.lock().unwrap()[reused].escaped = true;
+        release_native_callbacks();
+        unbind_slot(reused, reused_generation);
+        assert!(slot_function(reused).is_none());
+        let (next, _) = bind_slot(first).unwrap();
+        assert_ne!(next, reused);
+        assert!(slot_function(reused).is_none());
+    }
+}
@@ -0,0 +1,169 @@
+/*
+
+ Classes
+
+ Rust structs exposed as thinBasic classes, see #[thinbasic_class] for generating the registration.
+ Engine keeps one pointer sized slot per object, it holds the boxed Rust value.
+ Method calling back into script may meet the same object again, so the value is borrowed as handles are.
+
+*/
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::libloading;
+use super::{Engine, ExistingFunction, RegistrationError, ReturnType, RunTimeError, TBStr, Token, expect_token, raise_runtime_error};
+
+pub use super::thinbasic_macros::thinbasic_class;
+
+// Action passed to property functions
+pub const CLASS_ACTION_GET: i32 = 1;
+pub const CLASS_ACTION_SET: i32 = 2;
+
+// Constructors may fail, both plain value and Result are accepted
+pub trait Constructed<T>
+{
+    fn into_object(self) -> Result<T, RunTimeError>;
+}
+
+impl<T> Constructed<T> for T
+{
+    fn into_object(self) -> Result<T, RunTimeError> {
+        Ok(self)
+    }
+}
+
+impl<T> Constructed<T> for Result<T, RunTimeError>
+{
+    fn into_object(self) -> Result<T, RunTimeError> {
+        self
+    }
+}
+
+// Object is shared only while its member runs, destroying it meanwhile leaves the value to the member
+type ObjectSlot<T> = *mut Rc<RefCell<T>>;
+
+/// # Safety
+/// object_ptr must be the object slot the engine passed to class function
+pub unsafe fn object_create<T, C: Constructed<T>>(engine: Engine, object_ptr: usize, constructed: C)
+{
+    let slot = object_ptr as *mut ObjectSlot<T>;
+    object_destroy::<T>(object_ptr);
+
+    match constructed.into_object() {
+        Ok(object) => *slot = Box::into_raw(Box::new(Rc::new(RefCell::new(object)))),
+        Err(error) => {
+            raise_runtime_error(engine, error, "Object could not be created");
+        }
+    }
+}
+
+/// # Safety
+/// Same as object_create
+pub unsafe fn object_destroy<T>(object_ptr: usize)
+{
+    let slot = object_ptr as *mut ObjectSlot<T>;
+
+    if !slot.is_null() && !(*slot).is_null() {
+        drop(Box::from_raw(*slot));
+        *slot = ::std::ptr::null_mut();
+    }
+}
+
+/// # Safety
+/// Same as object_create
+pub unsafe fn with_object<T, R, F: FnOnce(&mut T) -> R>(engine: Engine, object_ptr: usize, action: F) -> Option<R>
+{
+    let slot = object_ptr as *mut ObjectSlot<T>;
+
+    if slot.is_null() || (*slot).is_null() {
+        raise_runtime_error(engine, RunTimeError::ClassNotInitWithNew, "Object must be created with NEW before use");
+        return None;
+    }
+
+    // Member calling script which uses the object again is refused instead of getting second &mut
+    let value = (**slot).clone();
+    let mut object = match value.try_borrow_mut() {
+        Ok(object) => object,
+        Err(_) => {
+            raise_runtime_error(engine, RunTimeError::ModuleSpecific, "Object is in use by its method or property");
+            return None;
+        }
+    };
+
+    Some(action(&mut object))
+}
+
+#[allow(dead_code)]
+pub fn property_not_found(engine: Engine, property_name: &str)
+{
+    raise_runtime_error(engine, RunTimeError::ClassMethodPropertyNotfound, property_name);
+}
+
+// Property has getter only
+#[allow(dead_code)]
+pub fn property_read_only(engine: Engine, property_name: &str)
+{
+    raise_runtime_error(engine, RunTimeError::ClassSetNowAllowed, &format!("{} cannot be assigned", property_name));
+}
+
+// Property assignment is followed by = and the new value
+#[allow(dead_code)]
+pub fn check_property_set(engine: Engine) -> bool
+{
+    expect_token(engine, Token::Equal).is_ok()
+}
+
+// Returns engine class pointer, the name must not be taken by other symbol
+#[allow(dead_code)]
+pub fn add_class(engine: Engine, class_name: &str) -> Result<usize, RegistrationError>
+{
+    let class_name = super::module::symbol_name(class_name);
+    super::registration_conflict(engine, &class_name, ExistingFunction::Fail)?;
+
+    unsafe
+    {
+        let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+        let thinbasic_class_add: libloading::Symbol<unsafe extern "system" fn(class_name: TBStr, class_function: usize) -> usize> = match lib.get(b"thinBasic_Class_Add") {
+            Ok(thinbasic_class_add) => thinbasic_class_add,
+            Err(_) => return Err(RegistrationError::Unsupported(class_name))
+        };
+
+        match thinbasic_class_add(TBStr::from(class_name.as_str()), 0) {
+            0 => Err(RegistrationError::Failed(class_name, 0)),
+            class_ptr => Ok(class_ptr)
+        }
+    }
+}
+
+// _Create and _Destroy methods are called by NEW and when the object goes out of scope, returns engine method id
+#[allow(dead_code)]
+pub fn add_class_method(_engine: Engine, class_ptr: usize, method_name: &str, return_type: ReturnType, method_function: usize) -> Result<i32, RegistrationError>
+{
+    unsafe
+    {
+        let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+        let thinbasic_class_addmethod: libloading::Symbol<unsafe extern "system" fn(class_ptr: usize, method_name: TBStr, return_type: i32, method_function: usize) -> i32> = match lib.get(b"thinBasic_Class_AddMethod") {
+            Ok(thinbasic_class_addmethod) => thinbasic_class_addmethod,
+            Err(_) => return Err(RegistrationError::Unsupported(String::from(method_name)))
+        };
+
+        RegistrationError::from_load_result(method_name, thinbasic_class_addmethod(class_ptr, TBStr::from(method_name), return_type as i32, method_function))
+    }
+}
+
+// Returns engine property id
+#[allow(dead_code)]
+pub fn add_class_property(_engine: Engine, class_ptr: usize, property_name: &str, return_type: ReturnType, property_function: usize) -> Result<i32, RegistrationError>
+{
+    unsafe
+    {
+        let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+        let thinbasic_class_addproperty: libloading::Symbol<unsafe extern "system" fn(class_ptr: usize, property_name: TBStr, return_type: i32, property_function: usize) -> i32> = match lib.get(b"thinBasic_Class_AddProperty") {
+            Ok(thinbasic_class_addproperty) => thinbasic_class_addproperty,
+            Err(_) => return Err(RegistrationError::Unsupported(String::from(property_name)))
+        };
+
+        RegistrationError::from_load_result(property_name, thinbasic_class_addproperty(class_ptr, TBStr::from(property_name), return_type as i32, property_function))
+    }
+}
@@ -0,0 +1,217 @@
+/*
+
+ Handles
+
+ Rust values owned by the module, passed to script as plain numbers.
+ All access happens on the script thread, so the table is thread local.
+
+*/
+
+use std::any::{Any, TypeId, type_name};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::{Engine, RunTimeError, raise_runtime_error};
+
+// Value stays in the table while in use, its RefCell tells nested access apart from released handles
+struct HandleEntry
+{
+    value: Rc<RefCell<Box<dyn Any>>>,
+    type_id: TypeId,
+    type_name: &'static str
+}
+
+struct HandleTable
+{
+    entries: HashMap<u32, HandleEntry>,
+    last_handle: u32
+}
+
+thread_local! {
+    static HANDLES: RefCell<HandleTable> = RefCell::new(HandleTable { entries: HashMap::new(), last_handle: 0 });
+}
+
+// Why handle cannot be used, kept apart from raising so the lookup can be tested without engine
+#[derive(Debug, Clone, PartialEq)]
+enum HandleError
+{
+    Stale,
+    WrongType { found: &'static str, expected: &'static str },
+    InUse
+}
+
+impl HandleError
+{
+    fn description(&self, handle: u32) -> String {
+        match self {
+            HandleError::Stale => format!("Invalid handle {}: not created or already released", handle),
+            HandleError::WrongType { found, expected } => format!("Invalid handle {}: refers to {}, expected {}", handle, found, expected),
+            HandleError::InUse => format!("Handle {} is in use", handle)
+        }
+    }
+}
+
+fn raise_handle_error(engine: Engine, handle: u32, error: HandleError) -> RunTimeError
+{
+    raise_runtime_error(engine, RunTimeError::ModuleSpecific, &error.description(handle));
+    RunTimeError::ModuleSpecific
+}
+
+// Takes ownership of value, returns non zero handle for script; handles are never reused
+#[allow(dead_code)]
+pub fn insert_handle<T: Any>(_engine: Engine, value: T) -> u32
+{
+    HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+
+        loop {
+            handles.last_handle = handles.last_handle.wrapping_add(1);
+            let handle = handles.last_handle;
+
+            if handle != 0 && !handles.entries.contains_key(&handle) {
+                let entry = HandleEntry { value: Rc::new(RefCell::new(Box::new(value))), type_id: TypeId::of::<T>(), type_name: type_name::<T>() };
+                handles.entries.insert(handle, entry);
+                return handle;
+            }
+        }
+    })
+}
+
+fn entry_value<T: Any>(handle: u32) -> Result<Rc<RefCell<Box<dyn Any>>>, HandleError>
+{
+    HANDLES.with(|handles| {
+        match handles.borrow().entries.get(&handle) {
+            None => Err(HandleError::Stale),
+            Some(entry) if entry.type_id != TypeId::of::<T>() => Err(HandleError::WrongType { found: entry.type_name, expected: type_name::<T>() }),
+            Some(entry) => Ok(entry.value.clone())
+        }
+    })
+}
+
+fn access_handle<T: Any, R, F: FnOnce(&mut T) -> R>(handle: u32, action: F) -> Result<R, HandleError>
+{
+    // Table is not borrowed during action, so action can use other handles
+    let value = entry_value::<T>(handle)?;
+    let mut value = value.try_borrow_mut().map_err(|_| HandleError::InUse)?;
+
+    Ok(action(value.downcast_mut::<T>().unwrap()))
+}
+
+fn take_handle<T: Any>(handle: u32) -> Result<T, HandleError>
+{
+    let value = entry_value::<T>(handle)?;
+    if value.try_borrow_mut().is_err() {
+        return Err(HandleError::InUse);
+    }
+    drop(value);
+
+    let entry = HANDLES.with(|handles| handles.borrow_mut().entries.remove(&handle)).unwrap();
+    match Rc::try_unwrap(entry.value) {
+        Ok(value) => Ok(*value.into_inner().downcast::<T>().unwrap()),
+        Err(_) => unreachable!("handle value is shared only during with_handle")
+    }
+}
+
+// Gives access to value behind handle, raises runtime error for stale handles, handles of other type or handles already in use
+#[allow(dead_code)]
+pub fn with_handle<T: Any, R, F: FnOnce(&mut T) -> R>(engine: Engine, handle: u32, action: F) -> Result<R, RunTimeError>
+{
+    access_handle(handle, action).map_err(|error| raise_handle_error(engine, handle, error))
+}
+
+// Releases handle and gives the value back to Rust
+#[allow(dead_code)]
+pub fn remove_handle<T: Any>(engine: Engine, handle: u32) -> Result<T, RunTimeError>
+{
+    take_handle(handle).map_err(|error| raise_handle_error(engine, handle, error))
+}
+
+#[allow(dead_code)]
+pub fn handle_exists(_engine: Engine, handle: u32) -> bool
+{
+    HANDLES.with(|handles| handles.borrow().entries.contains_key(&handle))
+}
+
+// Drops all values still referenced by script, call at module unload
+#[allow(dead_code)]
+pub fn release_handles(_engine: Engine)
+{
+    let entries = HANDLES.with(|handles| ::std::mem::take(&mut handles.borrow_mut().entries));
+
+    // Dropped outside of the borrow, values may release other handles when dropped
+    drop(entries);
+}
+
+// Raising links to OleAut32, so tests go through the lookup below with_handle and remove_handle
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn value_survives_panicking_action() {
+        let engine = unsafe { Engine::assume_script_thread() };
+        let handle = insert_handle(engine, 41u32);
+
+        let result = ::std::panic::catch_unwind(|| {
+            let _ = access_handle::<u32, (), _>(handle, |_| panic!("action failed"));
+        });
+        assert!(result.is_err());
+
+        assert_eq!(access_handle::<u32, _, _>(handle, |value| { *value += 1; *value }), Ok(42));
+        assert_eq!(take_handle::<u32>(handle), Ok(42));
+        assert!(!handle_exists(engine, handle));
+    }
+
+    #[test]
+    fn nested_access_to_other_handle() {
+        let engine = unsafe { Engine::assume_script_thread() };
+        let outer = insert_handle(engine, String::from("outer"));
+        let inner = insert_handle(engine, 7i32);
+
+        let length = access_handle::<String, _, _>(outer, |text| {
+            access_handle::<i32, _, _>(inner, |number| text.len() as i32 + *number)
+        });
+
+        assert_eq!(length, Ok(Ok(12)));
+    }
+
+    #[test]
+    fn stale_handle_is_refused() {
+        let engine = unsafe { Engine::assume_script_thread() };
+        let handle = insert_handle(engine, 1u8);
+        assert_eq!(take_handle::<u8>(handle), Ok(1));
+
+        assert_eq!(access_handle::<u8, _, _>(handle, |_| ()), Err(HandleError::Stale));
+        assert_eq!(take_handle::<u8>(handle), Err(HandleError::Stale));
+        assert_eq!(HandleError::Stale.description(handle), format!("Invalid handle {}: not created or already released", handle));
+    }
+
+    #[test]
+    fn handle_of_other_type_is_refused() {
+        let engine = unsafe { Engine::assume_script_thread() };
+        let handle = insert_handle(engine, 5i32);
+        let expected = HandleError::WrongType { found: type_name::<i32>(), expected: type_name::<String>() };
+
+        assert_eq!(access_handle::<String, _, _>(handle, |_| ()), Err(expected.clone()));
+        assert_eq!(take_handle::<String>(handle), Err(expected.clone()));
+        assert_eq!(expected.description(handle), format!("Invalid handle {}: refers to i32, expected alloc::string::String", handle));
+
+        // Refused lookup leaves the value in place
+        assert_eq!(take_handle::<i32>(handle), Ok(5));
+    }
+
+    #[test]
+    fn handle_in_use_is_refused() {
+        let engine = unsafe { Engine::assume_script_thread() };
+        let handle = insert_handle(engine, 3u16);
+
+        let nested = access_handle::<u16, _, _>(handle, |_| {
+            (access_handle::<u16, _, _>(handle, |_| ()), take_handle::<u16>(handle))
+        });
+
+        assert_eq!(nested, Ok((Err(HandleError::InUse), Err(HandleError::InUse))));
+        assert_eq!(take_handle::<u16>(handle), Ok(3));
+    }
+}
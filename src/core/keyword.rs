@@ -0,0 +1,216 @@
+/*
+
+ Keywords
+
+ Rust functions registered as thinBasic keywords through #[thinbasic_keyword].
+ The attribute generates the extern function the engine calls, parsing arguments by the keyword grammar,
+ and register_<function name>(engine) to add it in LoadLocalSymbols, returning Result<symbol id, RegistrationError>.
+ fail_if_exists and alias = "..." options go to add_function_with:
+     #[thinbasic_keyword(name = "MyCopy", fail_if_exists, alias = "MyCp")]
+
+ Grammar is written as in script, with {parameter} where function parameter is parsed:
+     "{source} TO {destination}"
+     "{file} FOR {mode:INPUT|OUTPUT|APPEND} AS #{channel}"
+ {parameter:A|B} accepts one of the keywords and passes index of the one found.
+ Without grammar the keyword is call shaped, (parameter, parameter, ...), and without required parameters () is optional.
+
+ Option<T> and #[default = value] parameters are optional, they are parsed only when the , or ( before them
+ in grammar is followed by argument. Vec<T> parameter keeps parsing arguments while comma follows.
+ In call shaped grammar optional parameters go after the required ones and Vec<T> is the last one.
+
+ Keywords with more forms are marked #[thinbasic_keyword(overload)] and grouped under one name:
+     thinbasic_overloads!(register_draw, "Draw", [draw_xy, draw_point, draw_text]);
+ The same registration options may follow the list of forms.
+ Arguments are parsed by what they start with and the first form accepting them all is called.
+ Keyword starts string argument only when its name ends with $, like Chr$, other keywords are parsed as numbers.
+
+*/
+
+use super::libloading;
+use super::{Engine, KeywordReturn, RunTimeError, TBStr, Token, VariableType, PeekedToken};
+use super::{accept_token, error_free, expect_token, parse_f64, parse_tbstr, peek_token, raise_runtime_error};
+
+pub use super::thinbasic_macros::{thinbasic_keyword, thinbasic_overloads};
+
+// Argument of overloaded keyword, parsed before the form is chosen
+pub enum ArgValue<'e>
+{
+    Number(f64),
+    Text(String),
+    Udt(UdtVariable<'e>)
+}
+
+// UDT variable passed to overloaded keyword, only parse_overload_args creates it
+#[derive(Debug, Clone, Copy)]
+pub struct UdtVariable<'e>
+{
+    var_ptr: usize,
+    data_ptr: *mut u8,
+    _engine: ::std::marker::PhantomData<&'e Engine>
+}
+
+impl<'e> UdtVariable<'e>
+{
+    pub fn var_ptr(&self) -> usize {
+        self.var_ptr
+    }
+
+    // Data of the element passed, null when the variable has none
+    pub fn data_ptr(&self) -> *mut u8 {
+        self.data_ptr
+    }
+}
+
+// Parameter type usable in overloaded keyword
+pub trait OverloadArg<'e>: Sized
+{
+    // Shown in the list of accepted forms
+    fn type_name() -> String;
+    fn accepts(arg: &ArgValue<'e>) -> bool;
+
+    /// # Safety
+    /// Only for argument accepts returned true for, dispatch_overload calls forms after checking that
+    unsafe fn from_arg(arg: &ArgValue<'e>) -> Self;
+}
+
+macro_rules! impl_overload_arg_number {
+    ($($t:ty),*) => {
+        $(
+            impl<'e> OverloadArg<'e> for $t
+            {
+                fn type_name() -> String {
+                    String::from("Number")
+                }
+
+                fn accepts(arg: &ArgValue<'e>) -> bool {
+                    matches!(*arg, ArgValue::Number(_))
+                }
+
+                unsafe fn from_arg(arg: &ArgValue<'e>) -> Self {
+                    match *arg {
+                        ArgValue::Number(number) => number as $t,
+                        _ => 0 as $t
+                    }
+                }
+            }
+        )*
+    }
+}
+
+impl_overload_arg_number!(u8, u16, u32, i16, i32, i64, f32, f64);
+
+impl<'e> OverloadArg<'e> for String
+{
+    fn type_name() -> String {
+        String::from("String")
+    }
+
+    fn accepts(arg: &ArgValue<'e>) -> bool {
+        matches!(*arg, ArgValue::Text(_))
+    }
+
+    unsafe fn from_arg(arg: &ArgValue<'e>) -> Self {
+        match *arg {
+            ArgValue::Text(ref text) => text.clone(),
+            _ => String::new()
+        }
+    }
+}
+
+impl<'e> OverloadArg<'e> for TBStr
+{
+    fn type_name() -> String {
+        String::from("String")
+    }
+
+    fn accepts(arg: &ArgValue<'e>) -> bool {
+        matches!(*arg, ArgValue::Text(_))
+    }
+
+    unsafe fn from_arg(arg: &ArgValue<'e>) -> Self {
+        match *arg {
+            ArgValue::Text(ref text) => TBStr::from(text.as_str()),
+            _ => TBStr::from("")
+        }
+    }
+}
+
+// One form of overloaded keyword, built by #[thinbasic_keyword(overload)]
+pub struct KeywordOverload<R: KeywordReturn>
+{
+    pub accepts: fn(&[ArgValue]) -> bool,
+    // Only for arguments accepts returned true for
+    pub call: unsafe fn(Engine, &[ArgValue]) -> R,
+    pub form: fn() -> String
+}
+
+fn parse_overload_arg(engine: &Engine) -> Result<ArgValue<'_>, RunTimeError>
+{
+    let token = peek_token(*engine);
+    if !error_free(*engine) {
+        return Err(super::get_last_error(*engine));
+    }
+
+    let arg = if token.is_string() {
+        ArgValue::Text(parse_tbstr(*engine).to_string())
+    } else if let PeekedToken::Variable(_, VariableType::UDT) = token {
+        unsafe {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_udt_getsize: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize) -> i32> = super::optional_export(*engine, &lib, "thinBasic_UDT_GetSize")?;
+
+            let (var_ptr, abs_pos) = super::parse_variable(*engine)?;
+
+            let (_, _, data_ptr) = super::variable_element_info(*engine, &lib, var_ptr, 1)?;
+            let data_ptr = if data_ptr.is_null() {
+                data_ptr
+            } else {
+                data_ptr.add(super::element_offset(abs_pos, thinbasic_udt_getsize(var_ptr).max(0) as usize))
+            };
+
+            ArgValue::Udt(UdtVariable { var_ptr, data_ptr, _engine: ::std::marker::PhantomData })
+        }
+    } else {
+        ArgValue::Number(parse_f64(*engine))
+    };
+
+    if error_free(*engine) { Ok(arg) } else { Err(super::get_last_error(*engine)) }
+}
+
+// Parses (argument, argument, ...), each as string, UDT variable or number by its first token
+pub fn parse_overload_args(engine: &Engine) -> Result<Vec<ArgValue<'_>>, RunTimeError>
+{
+    expect_token(*engine, Token::OpenParens)?;
+
+    let mut args = Vec::new();
+    if accept_token(*engine, Token::CloseParens) {
+        return Ok(args);
+    }
+
+    loop {
+        args.push(parse_overload_arg(engine)?);
+        if !accept_token(*engine, Token::Comma) {
+            break;
+        }
+    }
+
+    expect_token(*engine, Token::CloseParens)?;
+    Ok(args)
+}
+
+// Calls the first form accepting parsed arguments, error lists all forms otherwise
+pub fn dispatch_overload<R: KeywordReturn>(engine: Engine, keyword_name: &str, overloads: &[KeywordOverload<R>]) -> R
+{
+    let args = match parse_overload_args(&engine) {
+        Ok(args) => args,
+        Err(_) => return R::none()
+    };
+
+    match overloads.iter().find(|overload| (overload.accepts)(&args)) {
+        Some(overload) => unsafe { (overload.call)(engine, &args) },
+        None => {
+            let forms: Vec<String> = overloads.iter().map(|overload| format!("{}{}", keyword_name, (overload.form)())).collect();
+            raise_runtime_error(engine, RunTimeError::InvalidDataType, &format!("Accepted forms: {}", forms.join(", ")));
+            R::none()
+        }
+    }
+}
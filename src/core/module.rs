@@ -0,0 +1,353 @@
+/*
+
+ Module lifetime
+
+ State of the module lives from LoadLocalSymbols to UnLoadLocalSymbols, both generated by thinbasic_module!.
+ SYMBOL_PREFIX of the state is applied to everything registered, so the module can be renamed in one place.
+ Engine calls keywords on the script thread only, so the state is thread local.
+ Worker threads hand their results back through completion queue, processed by keywords on the script thread.
+
+*/
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::sync::{mpsc, Arc, Weak};
+use std::time::{Duration, Instant};
+
+use super::{Engine, EngineVersion, RunTimeError, MINIMUM_ENGINE_VERSION, check_engine_version, error_pending, raise_runtime_error};
+use super::callback::{CallArg, CallValue, ScriptFunction};
+
+// Implemented by module state, load is the place to register keywords, equates and types
+pub trait ModuleState: Sized + 'static
+{
+    // Put before names of keywords, equates, TYPEs and classes the module registers, like Rust_
+    const SYMBOL_PREFIX: &'static str = "";
+
+    // Keywords and equates are registered also without the prefix, plain name taken already is reported instead of overwritten
+    const UNPREFIXED_ALIASES: bool = false;
+
+    // Older engine refuses to load the module with runtime error instead of failing on missing exports
+    const MINIMUM_ENGINE_VERSION: EngineVersion = MINIMUM_ENGINE_VERSION;
+
+    fn load(engine: Engine) -> Self;
+
+    fn unload(&mut self, _engine: Engine) {}
+}
+
+thread_local! {
+    static STATE: RefCell<Option<Box<dyn Any>>> = RefCell::new(None);
+    static UNLOAD_CALLBACKS: RefCell<Vec<Box<dyn FnOnce()>>> = RefCell::new(Vec::new());
+    static COMPLETIONS: RefCell<Option<CompletionQueue>> = const { RefCell::new(None) };
+    static SYMBOL_PREFIX: RefCell<(String, bool)> = const { RefCell::new((String::new(), false)) };
+}
+
+// Entry points generated by thinbasic_module! call these
+
+#[allow(dead_code)]
+pub fn load<S: ModuleState>() -> i32
+{
+    // Engine loads the module on the thread which runs the script
+    let engine = unsafe { Engine::assume_script_thread() };
+
+    if let Err(description) = check_engine_version(engine, S::MINIMUM_ENGINE_VERSION) {
+        raise_runtime_error(engine, RunTimeError::ModuleSpecific, &description);
+        return -1;
+    }
+
+    set_symbol_prefix(S::SYMBOL_PREFIX, S::UNPREFIXED_ALIASES);
+
+    let state = S::load(engine);
+    STATE.with(|current| *current.borrow_mut() = Some(Box::new(state)));
+
+    0
+}
+
+#[allow(dead_code)]
+pub fn unload<S: ModuleState>() -> i32
+{
+    let engine = unsafe { Engine::assume_script_thread() };
+
+    // Callbacks run in reverse order of registration, before the state is gone
+    let callbacks = UNLOAD_CALLBACKS.with(|callbacks| ::std::mem::take(&mut *callbacks.borrow_mut()));
+    for callback in callbacks.into_iter().rev() {
+        callback();
+    }
+
+    let state = STATE.with(|current| current.borrow_mut().take());
+    if let Some(mut state) = state {
+        if let Some(state) = state.downcast_mut::<S>() {
+            state.unload(engine);
+        }
+    }
+
+    // Workers still running find the queue closed
+    COMPLETIONS.with(|completions| completions.borrow_mut().take());
+
+    super::handle::release_handles(engine);
+    super::callback::release_native_callbacks();
+    super::udt::release_registered_udts();
+
+    set_symbol_prefix("", false);
+
+    0
+}
+
+// Gives keyword access to module state
+#[allow(dead_code)]
+pub fn with_state<S: ModuleState, R, F: FnOnce(&mut S) -> R>(engine: Engine, action: F) -> Result<R, RunTimeError>
+{
+    STATE.with(|current| {
+        let mut current = match current.try_borrow_mut() {
+            Ok(current) => current,
+            Err(_) => {
+                raise_runtime_error(engine, RunTimeError::ModuleSpecific, "Module state is already in use");
+                return Err(RunTimeError::ModuleSpecific);
+            }
+        };
+
+        match current.as_mut().and_then(|state| state.downcast_mut::<S>()) {
+            Some(state) => Ok(action(state)),
+            None => {
+                raise_runtime_error(engine, RunTimeError::ModuleSpecific, "Module state is not loaded");
+                Err(RunTimeError::ModuleSpecific)
+            }
+        }
+    })
+}
+
+// Registers cleanup to run at UnLoadLocalSymbols
+#[allow(dead_code)]
+pub fn on_unload<F: FnOnce() + 'static>(callback: F)
+{
+    UNLOAD_CALLBACKS.with(|callbacks| callbacks.borrow_mut().push(Box::new(callback)));
+}
+
+/*
+
+ Symbol prefix
+
+*/
+
+pub(super) fn set_symbol_prefix(prefix: &str, unprefixed_aliases: bool)
+{
+    SYMBOL_PREFIX.with(|current| *current.borrow_mut() = (String::from(prefix), unprefixed_aliases));
+}
+
+// Name with module prefix, equates keep their $ or % in front
+#[allow(dead_code)]
+pub fn symbol_name(name: &str) -> String
+{
+    SYMBOL_PREFIX.with(|prefix| {
+        let prefix = &prefix.borrow().0;
+
+        match name.chars().next() {
+            Some(sigil) if sigil == '$' || sigil == '%' => format!("{}{}{}", sigil, prefix, &name[1..]),
+            _ => format!("{}{}", prefix, name)
+        }
+    })
+}
+
+// Names keyword or equate is registered under, prefixed one first
+#[allow(dead_code)]
+pub fn symbol_names(name: &str) -> Vec<String>
+{
+    let prefixed = symbol_name(name);
+    let unprefixed_alias = SYMBOL_PREFIX.with(|prefix| prefix.borrow().1);
+
+    if unprefixed_alias && prefixed != name {
+        vec![prefixed, String::from(name)]
+    } else {
+        vec![prefixed]
+    }
+}
+
+/*
+
+ Completions
+
+*/
+
+type Completion = Box<dyn FnOnce(Engine) + Send>;
+
+// Script thread keeps only the receiver, queue disconnects once every sender is dropped
+struct CompletionQueue
+{
+    receiver: mpsc::Receiver<Completion>,
+    sender: Weak<mpsc::Sender<Completion>>
+}
+
+// How often waiting checks whether the script was stopped
+const WAIT_SLICE: Duration = Duration::from_millis(50);
+
+// Worker side of the completion queue, can be cloned and moved to any thread
+#[derive(Clone)]
+pub struct CompletionSender
+{
+    sender: Arc<mpsc::Sender<Completion>>
+}
+
+impl CompletionSender
+{
+    // Completion runs on the script thread when a keyword polls or waits, false once the module is unloaded
+    pub fn post<F: FnOnce(Engine) + Send + 'static>(&self, completion: F) -> bool {
+        self.sender.send(Box::new(completion)).is_ok()
+    }
+
+    // Calls script function with given arguments on the script thread, its result is ignored
+    pub fn post_callback(&self, function: ScriptFunction, args: Vec<CallValue>) -> bool {
+        self.post(move |engine| {
+            let args: Vec<CallArg> = args.iter().map(|arg| arg.as_arg()).collect();
+            let _ = function.call::<()>(engine, &args);
+        })
+    }
+}
+
+// Sender for worker threads, queue lives until the module is unloaded
+#[allow(dead_code)]
+pub fn completion_sender(_engine: Engine) -> CompletionSender
+{
+    COMPLETIONS.with(|completions| {
+        let mut completions = completions.borrow_mut();
+
+        if let Some(sender) = completions.as_ref().and_then(|queue| queue.sender.upgrade()) {
+            return CompletionSender { sender };
+        }
+
+        // Earlier senders are all gone, what they posted moves to the new queue
+        let (sender, receiver) = mpsc::channel();
+        if let Some(previous) = completions.take() {
+            for completion in previous.receiver.try_iter() {
+                let _ = sender.send(completion);
+            }
+        }
+
+        let sender = Arc::new(sender);
+        *completions = Some(CompletionQueue { receiver, sender: Arc::downgrade(&sender) });
+
+        CompletionSender { sender }
+    })
+}
+
+fn run_completions(engine: Engine, pending: Vec<Completion>) -> usize
+{
+    let count = pending.len();
+    for completion in pending {
+        completion(engine);
+    }
+
+    count
+}
+
+// Runs completions posted so far without blocking, returns how many ran.
+// Nothing runs while the keyword holds VariableRef or ArrayRef, as for ScriptFunction::call.
+#[allow(dead_code)]
+pub fn poll_completions(engine: Engine) -> usize
+{
+    if super::check_script_reentry(engine).is_err() {
+        return 0;
+    }
+
+    // Collected first, completions may post or poll again
+    let pending: Vec<Completion> = COMPLETIONS.with(|completions| match *completions.borrow() {
+        Some(ref queue) => queue.receiver.try_iter().collect(),
+        None => Vec::new()
+    });
+
+    run_completions(engine, pending)
+}
+
+// Blocks until at least one completion arrives, then runs all pending ones.
+// Returns early when timeout passes, the script is stopped or no worker holds a sender anymore, Duration::MAX waits without timeout.
+// Returns 0 at once while the keyword holds VariableRef or ArrayRef.
+#[allow(dead_code)]
+pub fn wait_completions(engine: Engine, timeout: Duration) -> usize
+{
+    if super::check_script_reentry(engine).is_err() {
+        return 0;
+    }
+
+    // Timeout too long to add, like Duration::MAX, means no deadline
+    let deadline = Instant::now().checked_add(timeout);
+
+    let pending: Vec<Completion> = COMPLETIONS.with(|completions| match *completions.borrow() {
+        Some(ref queue) => {
+            let first = loop {
+                let remaining = deadline.map_or(Duration::MAX, |deadline| deadline.saturating_duration_since(Instant::now()));
+
+                match queue.receiver.recv_timeout(remaining.min(WAIT_SLICE)) {
+                    Ok(completion) => break Some(completion),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break None,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if remaining <= WAIT_SLICE || error_pending(engine) {
+                            break None;
+                        }
+                    }
+                }
+            };
+
+            first.into_iter().chain(queue.receiver.try_iter()).collect()
+        }
+        None => Vec::new()
+    });
+
+    run_completions(engine, pending)
+}
+
+// Generates LoadLocalSymbols and UnLoadLocalSymbols exports for module state type
+#[macro_export]
+macro_rules! thinbasic_module {
+    ($state:ty) => {
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        pub extern "system" fn LoadLocalSymbols(_module_path: *const u8) -> i32 {
+            $crate::core::module::load::<$state>()
+        }
+
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        pub extern "system" fn UnLoadLocalSymbols() -> i32 {
+            $crate::core::module::unload::<$state>()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{set_symbol_prefix as set_prefix, symbol_name, symbol_names};
+
+    #[test]
+    fn symbol_name_without_prefix_is_unchanged() {
+        set_prefix("", false);
+
+        assert_eq!(symbol_name("Test_Add"), "Test_Add");
+        assert_eq!(symbol_name("$Title"), "$Title");
+    }
+
+    #[test]
+    fn symbol_name_keeps_sigil_in_front() {
+        set_prefix("My_", false);
+
+        assert_eq!(symbol_name("Add"), "My_Add");
+        assert_eq!(symbol_name("$Title"), "$My_Title");
+        assert_eq!(symbol_name("%Max"), "%My_Max");
+    }
+
+    #[test]
+    fn symbol_names_add_unprefixed_alias_when_enabled() {
+        set_prefix("My_", true);
+
+        assert_eq!(symbol_names("Add"), vec!["My_Add", "Add"]);
+        assert_eq!(symbol_names("%Max"), vec!["%My_Max", "%Max"]);
+    }
+
+    #[test]
+    fn symbol_names_without_alias() {
+        set_prefix("My_", false);
+        assert_eq!(symbol_names("$Title"), vec!["$My_Title"]);
+
+        // Without prefix the alias would repeat the name
+        set_prefix("", true);
+        assert_eq!(symbol_names("Add"), vec!["Add"]);
+    }
+}
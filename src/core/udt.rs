@@ -0,0 +1,373 @@
+/*
+
+ User defined types
+
+ Rust structs deriving Udt can be matched with TYPE variables passed by script,
+ and their TYPE declaration can be registered so scripts do not need to repeat it.
+
+*/
+
+use super::libloading;
+use super::{Engine, Ext, RunTimeError, TBStr, VariableType, raise_runtime_error};
+use super::keyword::{ArgValue, OverloadArg};
+use super::module::symbol_name;
+
+pub use super::thinbasic_macros::Udt;
+
+// One element of Rust struct, as described by #[derive(Udt)]
+#[derive(Debug, Clone, Copy)]
+pub struct UdtElement
+{
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize
+}
+
+/// # Safety
+/// Implemented by #[derive(Udt)], which checks that ELEMENTS describe the struct layout
+pub unsafe trait Udt: UdtField
+{
+    const NAME: &'static str;
+    const ELEMENTS: &'static [UdtElement];
+
+    // TYPE ... END TYPE, or UNION ... END UNION, source code
+    fn declaration() -> String;
+
+    // Registers nested TYPEs used by elements
+    fn register_element_udts(engine: Engine);
+}
+
+/// # Safety
+/// Only plain data which can be copied to and from script memory byte by byte
+pub unsafe trait UdtField: Copy
+{
+    // thinBasic type used in element declaration
+    fn tb_type() -> String;
+
+    // Array dimensions in Rust order, outermost first
+    fn dimensions(_dimensions: &mut Vec<usize>) {}
+
+    // Nested TYPEs must be known to the engine before the TYPE using them
+    fn register_dependencies(_engine: Engine) {}
+}
+
+macro_rules! impl_udt_field {
+    ($($t:ty => $tb_type:expr),*) => {
+        $(
+            unsafe impl UdtField for $t
+            {
+                fn tb_type() -> String {
+                    String::from($tb_type)
+                }
+            }
+        )*
+    }
+}
+
+impl_udt_field!(u8  => "BYTE",
+                i16 => "INTEGER",
+                u16 => "WORD",
+                u32 => "DWORD",
+                i32 => "LONG",
+                i64 => "QUAD",
+                f32 => "SINGLE",
+                f64 => "DOUBLE",
+                Ext => "EXT");
+
+unsafe impl<T: UdtField, const N: usize> UdtField for [T; N]
+{
+    fn tb_type() -> String {
+        T::tb_type()
+    }
+
+    fn dimensions(dimensions: &mut Vec<usize>) {
+        dimensions.push(N);
+        T::dimensions(dimensions);
+    }
+
+    fn register_dependencies(engine: Engine) {
+        T::register_dependencies(engine);
+    }
+}
+
+// STRING * N element, padded with spaces like in thinBasic
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct FixedString<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> FixedString<N>
+{
+    // Stored in ANSI code page like other script strings, longer texts are truncated to N bytes
+    pub fn new(text: &str) -> FixedString<N> {
+        let mut bytes = [b' '; N];
+        for (byte, &text_byte) in bytes.iter_mut().zip(super::ansi_from_str(text).iter()) {
+            *byte = text_byte;
+        }
+
+        FixedString(bytes)
+    }
+}
+
+impl<const N: usize> ::std::fmt::Display for FixedString<N>
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let text = super::string_from_ansi(&self.0);
+        f.write_str(text.trim_end_matches([' ', '\0']))
+    }
+}
+
+unsafe impl<const N: usize> UdtField for FixedString<N>
+{
+    fn tb_type() -> String {
+        format!("STRING * {}", N)
+    }
+}
+
+// Line of TYPE declaration, thinBasic lists array dimensions with the first index changing fastest
+pub fn element_declaration<T: UdtField>(element_name: &str) -> String
+{
+    let mut dimensions = Vec::new();
+    T::dimensions(&mut dimensions);
+
+    if dimensions.is_empty() {
+        format!("  {} AS {}\n", element_name, T::tb_type())
+    } else {
+        let dimensions: Vec<String> = dimensions.iter().rev().map(|dimension| dimension.to_string()).collect();
+        format!("  {}({}) AS {}\n", element_name, dimensions.join(", "), T::tb_type())
+    }
+}
+
+// Engine packs TYPE elements without gaps, filler BYTE arrays reproduce #[repr(C)] padding
+pub struct DeclarationLayout
+{
+    position: usize,
+    fillers: usize,
+    is_union: bool
+}
+
+impl DeclarationLayout
+{
+    pub fn new(is_union: bool) -> Self {
+        DeclarationLayout { position: 0, fillers: 0, is_union }
+    }
+
+    // Filler needed before element at given offset
+    pub fn element(&mut self, offset: usize, size: usize) -> String {
+        let filler = if !self.is_union && offset > self.position { self.filler(offset - self.position) } else { String::new() };
+        self.position = self.position.max(offset + size);
+
+        filler
+    }
+
+    // Trailing filler, so the TYPE has the size of the Rust type, union filler starts at 0 like every union element
+    pub fn end(&mut self, size: usize) -> String {
+        if self.position >= size {
+            return String::new();
+        }
+
+        let filler = if self.is_union { self.filler(size) } else { self.filler(size - self.position) };
+        self.position = size;
+
+        filler
+    }
+
+    fn filler(&mut self, size: usize) -> String {
+        self.fillers += 1;
+        format!("  RustPadding{}({}) AS BYTE\n", self.fillers, size)
+    }
+}
+
+// Names of TYPEs this module already registered, nested TYPEs can be shared
+static REGISTERED_UDTS: ::std::sync::Mutex<Vec<&'static str>> = ::std::sync::Mutex::new(Vec::new());
+
+// Makes TYPE declaration of T, and of TYPEs it contains, available to script, returns engine result, 0 on engine without thinBasic_AddUdt
+#[allow(dead_code)]
+pub fn register_udt<T: Udt>(engine: Engine) -> i32
+{
+    if REGISTERED_UDTS.lock().unwrap().contains(&T::NAME) {
+        return 1;
+    }
+
+    T::register_element_udts(engine);
+
+    unsafe
+    {
+        let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+        let thinbasic_addudt: libloading::Symbol<unsafe extern "system" fn(udt_code: TBStr) -> i32> = match super::optional_export(engine, &lib, "thinBasic_AddUdt") {
+            Ok(thinbasic_addudt) => thinbasic_addudt,
+            Err(_) => return 0
+        };
+
+        let result = thinbasic_addudt(TBStr::from(T::declaration().as_str()));
+        if result > 0 {
+            REGISTERED_UDTS.lock().unwrap().push(T::NAME);
+        }
+
+        result
+    }
+}
+
+// Engine forgets TYPEs when the script ends, called by module unload
+#[allow(dead_code)]
+pub fn release_registered_udts()
+{
+    REGISTERED_UDTS.lock().unwrap().clear();
+}
+
+// TYPE variable passed to a keyword, meant to be used until the keyword returns as VariableRef
+pub struct UdtRef<'e, T: Udt>
+{
+    data_ptr: *mut u8,
+    _udt: ::std::marker::PhantomData<(T, &'e Engine)>
+}
+
+impl<'e, T: Udt> UdtRef<'e, T>
+{
+    pub fn get(&self) -> T {
+        unsafe {
+            ::std::ptr::read_unaligned(self.data_ptr as *const T)
+        }
+    }
+
+    pub fn set(&self, value: &T) {
+        unsafe {
+            ::std::ptr::write_unaligned(self.data_ptr as *mut T, *value);
+        }
+    }
+
+    // Script memory is not guaranteed to be aligned, so changes go through a copy
+    pub fn update<F: FnOnce(&mut T)>(&self, change: F) {
+        let mut value = self.get();
+        change(&mut value);
+        self.set(&value);
+    }
+}
+
+// Checks the script TYPE provides every element of T at the same offset and with the same size.
+// Engine without thinBasic_UDT_GetElementSize cannot tell a STRING element from a LONG, so the binding is refused there.
+unsafe fn validate_udt<T: Udt>(lib: &libloading::Library, var_ptr: usize) -> Result<usize, (RunTimeError, String)>
+{
+    // Not raised here, overload resolution only asks whether the variable fits
+    let thinbasic_udt_getsize: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize) -> i32> = lib.get(b"thinBasic_UDT_GetSize").map_err(|_| super::export_missing("thinBasic_UDT_GetSize"))?;
+    let thinbasic_udt_getelementoffset: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize, element_name: TBStr) -> i32> = lib.get(b"thinBasic_UDT_GetElementOffset").map_err(|_| super::export_missing("thinBasic_UDT_GetElementOffset"))?;
+    let thinbasic_udt_getelementsize: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize, element_name: TBStr) -> i32> = lib.get(b"thinBasic_UDT_GetElementSize").map_err(|_| super::export_missing("thinBasic_UDT_GetElementSize"))?;
+
+    let udt_size = thinbasic_udt_getsize(var_ptr).max(0) as usize;
+    if udt_size < ::std::mem::size_of::<T>() {
+        return Err((RunTimeError::InternalUdtBufferShort,
+                    format!("{} needs {} bytes, variable has {}", symbol_name(T::NAME), ::std::mem::size_of::<T>(), udt_size)));
+    }
+
+    for element in T::ELEMENTS {
+        let offset = thinbasic_udt_getelementoffset(var_ptr, TBStr::from(element.name));
+
+        if offset < 0 {
+            return Err((RunTimeError::UdtElementNotFound, format!("{}.{} not found", symbol_name(T::NAME), element.name)));
+        }
+        if offset as usize != element.offset {
+            return Err((RunTimeError::UdtElementNotFound,
+                        format!("{}.{} expected at offset {}, found at {}", symbol_name(T::NAME), element.name, element.offset, offset)));
+        }
+
+        let size = thinbasic_udt_getelementsize(var_ptr, TBStr::from(element.name));
+        if size < 0 || size as usize != element.size {
+            return Err((RunTimeError::InvalidDataType,
+                        format!("{}.{} expected with {} bytes, found with {}", symbol_name(T::NAME), element.name, element.size, size)));
+        }
+    }
+
+    Ok(udt_size)
+}
+
+// Parses TYPE variable passed as keyword argument and checks it matches T
+#[allow(dead_code)]
+pub fn parse_udt<T: Udt>(engine: &Engine) -> Result<UdtRef<'_, T>, RunTimeError>
+{
+    let engine = *engine;
+
+    unsafe
+    {
+        let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+        let (var_ptr, abs_pos) = super::parse_variable(engine)?;
+
+        let (var_type, _, data_ptr) = super::variable_element_info(engine, &lib, var_ptr, 1)?;
+        if var_type != VariableType::UDT || data_ptr.is_null() {
+            raise_runtime_error(engine, RunTimeError::UdtExpected, &symbol_name(T::NAME));
+            return Err(RunTimeError::UdtExpected);
+        }
+
+        match validate_udt::<T>(&lib, var_ptr) {
+            Ok(udt_size) => {
+                let data_ptr = data_ptr.add(super::element_offset(abs_pos, udt_size));
+
+                Ok(UdtRef { data_ptr, _udt: ::std::marker::PhantomData })
+            }
+
+            Err((error, description)) => {
+                raise_runtime_error(engine, error, &description);
+                Err(error)
+            }
+        }
+    }
+}
+
+// UDT variables take part in keyword overloading, the form is chosen by TYPE layout
+impl<'e, T: Udt> OverloadArg<'e> for UdtRef<'e, T>
+{
+    fn type_name() -> String {
+        symbol_name(T::NAME)
+    }
+
+    fn accepts(arg: &ArgValue<'e>) -> bool {
+        match *arg {
+            ArgValue::Udt(ref variable) => unsafe {
+                let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+                !variable.data_ptr().is_null() && validate_udt::<T>(&lib, variable.var_ptr()).is_ok()
+            },
+            _ => false
+        }
+    }
+
+    // Element pointer was computed when parsing, accepts checked the layout
+    unsafe fn from_arg(arg: &ArgValue<'e>) -> Self {
+        match *arg {
+            ArgValue::Udt(ref variable) => UdtRef { data_ptr: variable.data_ptr(), _udt: ::std::marker::PhantomData },
+            _ => unreachable!("{} accepts only UDT variables", symbol_name(T::NAME))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::DeclarationLayout;
+
+    #[test]
+    fn layout_fills_gaps_and_tail() {
+        // struct { u8, f64, u8 } with repr(C)
+        let mut layout = DeclarationLayout::new(false);
+
+        assert_eq!(layout.element(0, 1), "");
+        assert_eq!(layout.element(8, 8), "  RustPadding1(7) AS BYTE\n");
+        assert_eq!(layout.element(16, 1), "");
+        assert_eq!(layout.end(24), "  RustPadding2(7) AS BYTE\n");
+    }
+
+    #[test]
+    fn layout_of_packed_struct_has_no_fillers() {
+        let mut layout = DeclarationLayout::new(false);
+
+        assert_eq!(layout.element(0, 4), "");
+        assert_eq!(layout.element(4, 4), "");
+        assert_eq!(layout.end(8), "");
+    }
+
+    #[test]
+    fn union_filler_covers_whole_union() {
+        let mut layout = DeclarationLayout::new(true);
+
+        assert_eq!(layout.element(0, 1), "");
+        assert_eq!(layout.element(0, 3), "");
+        assert_eq!(layout.end(4), "  RustPadding1(4) AS BYTE\n");
+    }
+}
@@ -9,7 +9,14 @@ pub mod core
 {
     extern crate libloading;    // For thinCore
     extern crate winapi;
-    extern crate ascii;
+    extern crate thinbasic_macros;
+
+    pub mod udt;
+    pub mod callback;
+    pub mod class;
+    pub mod handle;
+    pub mod module;
+    pub mod keyword;
 
     // Adjusting the signatures for our use
     extern "system" {
@@ -25,6 +32,94 @@ pub mod core
         pub fn SysStringByteLen(
             bstr: *const u8
         ) -> u32;    
+
+        pub fn GetFileVersionInfoSizeA(
+            lptstrFilename: *const u8,
+            lpdwHandle: *mut u32
+        ) -> u32;
+
+        pub fn GetFileVersionInfoA(
+            lptstrFilename: *const u8,
+            dwHandle: u32,
+            dwLen: u32,
+            lpData: *mut u8
+        ) -> i32;
+
+        pub fn VerQueryValueA(
+            pBlock: *const u8,
+            lpSubBlock: *const u8,
+            lplpBuffer: *mut *const u8,
+            puLen: *mut u32
+        ) -> i32;
+
+        pub fn GetModuleHandleA(
+            lpModuleName: *const u8
+        ) -> usize;
+
+        pub fn GetModuleFileNameA(
+            hModule: usize,
+            lpFilename: *mut u8,
+            nSize: u32
+        ) -> u32;
+
+        pub fn MultiByteToWideChar(
+            CodePage: u32,
+            dwFlags: u32,
+            lpMultiByteStr: *const u8,
+            cbMultiByte: i32,
+            lpWideCharStr: *mut u16,
+            cchWideChar: i32
+        ) -> i32;
+
+        pub fn WideCharToMultiByte(
+            CodePage: u32,
+            dwFlags: u32,
+            lpWideCharStr: *const u16,
+            cchWideChar: i32,
+            lpMultiByteStr: *mut u8,
+            cbMultiByte: i32,
+            lpDefaultChar: *const u8,
+            lpUsedDefaultChar: *mut i32
+        ) -> i32;
+    }
+
+    // thinBasic strings are bytes in the ANSI code page of the system
+    const CP_ACP: u32 = 0;
+
+    // Characters the code page lacks become ?, as in any other ANSI program
+    fn ansi_from_str(text: &str) -> Vec<u8>
+    {
+        let wide: Vec<u16> = text.encode_utf16().collect();
+        if wide.is_empty() {
+            return Vec::new();
+        }
+
+        unsafe
+        {
+            let len = WideCharToMultiByte(CP_ACP, 0, wide.as_ptr(), wide.len() as i32, ::std::ptr::null_mut(), 0, ::std::ptr::null(), ::std::ptr::null_mut());
+            let mut bytes = vec![0u8; len.max(0) as usize];
+            let len = WideCharToMultiByte(CP_ACP, 0, wide.as_ptr(), wide.len() as i32, bytes.as_mut_ptr(), bytes.len() as i32, ::std::ptr::null(), ::std::ptr::null_mut());
+            bytes.truncate(len.max(0) as usize);
+
+            bytes
+        }
+    }
+
+    fn string_from_ansi(bytes: &[u8]) -> String
+    {
+        if bytes.is_empty() {
+            return String::new();
+        }
+
+        unsafe
+        {
+            let len = MultiByteToWideChar(CP_ACP, 0, bytes.as_ptr(), bytes.len() as i32, ::std::ptr::null_mut(), 0);
+            let mut wide = vec![0u16; len.max(0) as usize];
+            let len = MultiByteToWideChar(CP_ACP, 0, bytes.as_ptr(), bytes.len() as i32, wide.as_mut_ptr(), wide.len() as i32);
+            wide.truncate(len.max(0) as usize);
+
+            String::from_utf16_lossy(&wide)
+        }
     }
 
     // Custom TBStr
@@ -36,9 +131,9 @@ pub mod core
         fn from(str_text: &'a str) -> Self {
             unsafe
             {
-                let ascii_str = ascii::AsciiStr::from_ascii(str_text).unwrap();
-                let byte_slice = ascii_str.as_bytes();
-                let ptr = SysAllocStringByteLen(&byte_slice[0], ascii_str.len());
+                // Text the code page cannot represent does not read back unchanged
+                let byte_slice = ansi_from_str(str_text);
+                let ptr = SysAllocStringByteLen(byte_slice.as_ptr(), byte_slice.len());
 
                 TBStr(ptr)
             }
@@ -65,16 +160,218 @@ pub mod core
             }
         }
         
-        pub fn to_string<'v>(&self) -> String {
-            unsafe {
-                let len = self.len();            
-                let slice: &[u8] = ::std::slice::from_raw_parts(self.0, len as usize);
+        // Bytes as the engine holds them, in ANSI code page
+        pub fn as_bytes(&self) -> &[u8] {
+            if self.0.is_null() {
+                return &[];
+            }
 
-                String::from(::std::str::from_utf8(slice).unwrap())
+            unsafe {
+                ::std::slice::from_raw_parts(self.0, self.len() as usize)
             }
+        }
+
+        pub fn to_string<'v>(&self) -> String {
+            string_from_ansi(self.as_bytes())
         }      
     }
 
+    /*
+
+     Engine access
+
+    */
+
+    thread_local! {
+        static SCRIPT_THREAD: ::std::cell::Cell<bool> = const { ::std::cell::Cell::new(false) };
+    }
+
+    // Token proving the code runs on the script thread, thinCore is single threaded so every engine call needs it.
+    // It cannot be moved to other threads, which makes calling the engine from a worker thread a compile error.
+    ///
+    /// ```compile_fail
+    /// fn assert_send<T: Send>() {}
+    /// assert_send::<thinbasic::core::Engine>();
+    /// ```
+    #[derive(Clone, Copy)]
+    pub struct Engine
+    {
+        _not_send: ::std::marker::PhantomData<*const ()>
+    }
+
+    impl Engine
+    {
+        // Available on the thread which loaded the module, None elsewhere
+        pub fn current() -> Option<Engine> {
+            if SCRIPT_THREAD.with(|script_thread| script_thread.get()) {
+                Some(Engine { _not_send: ::std::marker::PhantomData })
+            } else {
+                None
+            }
+        }
+
+        /// # Safety
+        /// Must be called on the thread running the script, typically at the start of function called by the engine
+        pub unsafe fn assume_script_thread() -> Engine {
+            SCRIPT_THREAD.with(|script_thread| script_thread.set(true));
+
+            Engine { _not_send: ::std::marker::PhantomData }
+        }
+    }
+
+    /*
+
+     Engine version
+
+    */
+
+    // Version of thinCore.dll, ordered so versions can be compared
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct EngineVersion
+    {
+        pub major: u16,
+        pub minor: u16,
+        pub build: u16,
+        pub revision: u16
+    }
+
+    impl EngineVersion
+    {
+        pub const fn new(major: u16, minor: u16, build: u16, revision: u16) -> EngineVersion {
+            EngineVersion { major, minor, build, revision }
+        }
+    }
+
+    impl ::std::fmt::Display for EngineVersion
+    {
+        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+            write!(f, "{}.{}.{}.{}", self.major, self.minor, self.build, self.revision)
+        }
+    }
+
+    // Oldest thinBasic the crate supports, exports added later are checked before use
+    pub const MINIMUM_ENGINE_VERSION: EngineVersion = EngineVersion::new(1, 10, 5, 0);
+
+    // Fixed part of version resource, only the leading fields are needed
+    #[repr(C)]
+    struct FixedFileInfo
+    {
+        signature: u32,
+        struct_version: u32,
+        file_version_ms: u32,
+        file_version_ls: u32
+    }
+
+    const FIXED_FILE_INFO_SIGNATURE: u32 = 0xFEEF04BD;
+
+    // Full path of thinCore.dll loaded in the process, other copies may be found first on the DLL search path
+    unsafe fn loaded_engine_path() -> Option<Vec<u8>>
+    {
+        let module = GetModuleHandleA(b"thinCore.dll\0".as_ptr());
+        if module == 0 {
+            return None;
+        }
+
+        // Path filling the whole buffer may be truncated, so the buffer grows until it fits
+        let mut path = vec![0u8; 260];
+        loop {
+            let len = GetModuleFileNameA(module, path.as_mut_ptr(), path.len() as u32) as usize;
+            if len == 0 {
+                return None;
+            }
+            if len < path.len() {
+                path.truncate(len);
+                path.push(0);
+                return Some(path);
+            }
+            if path.len() >= 32768 {
+                return None;
+            }
+
+            let grown = path.len() * 2;
+            path.resize(grown, 0);
+        }
+    }
+
+    // Read from version resource of the loaded thinCore.dll, None when it has none
+    #[allow(dead_code)]
+    pub fn engine_version(_engine: Engine) -> Option<EngineVersion>
+    {
+        unsafe
+        {
+            let file_name = loaded_engine_path()?;
+
+            let mut handle: u32 = 0;
+            let size = GetFileVersionInfoSizeA(file_name.as_ptr(), &mut handle);
+            if size == 0 {
+                return None;
+            }
+
+            let mut data = vec![0u8; size as usize];
+            if GetFileVersionInfoA(file_name.as_ptr(), 0, size, data.as_mut_ptr()) == 0 {
+                return None;
+            }
+
+            let mut info: *const FixedFileInfo = ::std::ptr::null();
+            let mut info_len: u32 = 0;
+            if VerQueryValueA(data.as_ptr(), b"\\\0".as_ptr(), &mut info as *mut *const FixedFileInfo as *mut *const u8, &mut info_len) == 0
+                || info.is_null() || (info_len as usize) < ::std::mem::size_of::<FixedFileInfo>() {
+                return None;
+            }
+
+            let info = ::std::ptr::read_unaligned(info);
+            if info.signature != FIXED_FILE_INFO_SIGNATURE {
+                return None;
+            }
+
+            Some(EngineVersion::new((info.file_version_ms >> 16) as u16, info.file_version_ms as u16,
+                                    (info.file_version_ls >> 16) as u16, info.file_version_ls as u16))
+        }
+    }
+
+    // Error description when engine is older than required, unknown version is accepted
+    #[allow(dead_code)]
+    pub fn check_engine_version(engine: Engine, minimum: EngineVersion) -> Result<(), String>
+    {
+        version_requirement(engine_version(engine), minimum)
+    }
+
+    fn version_requirement(version: Option<EngineVersion>, minimum: EngineVersion) -> Result<(), String>
+    {
+        match version {
+            Some(version) if version < minimum => Err(format!("Module requires thinBasic {} or newer, running on {}", minimum, version)),
+            _ => Ok(())
+        }
+    }
+
+    // Exports differ between engine versions, features built on newer ones can check first
+    #[allow(dead_code)]
+    pub fn export_available(_engine: Engine, export_name: &str) -> bool
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+
+            lib.get::<unsafe extern "system" fn()>(export_name.as_bytes()).is_ok()
+        }
+    }
+
+    // Export of newer thinCore, missing one is raised as runtime error instead of panicking
+    unsafe fn optional_export<'l, T>(engine: Engine, lib: &'l libloading::Library, export_name: &str) -> Result<libloading::Symbol<'l, T>, RunTimeError>
+    {
+        lib.get(export_name.as_bytes()).map_err(|_| {
+            let (error, description) = export_missing(export_name);
+            raise_runtime_error(engine, error, &description);
+            error
+        })
+    }
+
+    // Same error as optional_export, for checks which decide later whether to raise it
+    fn export_missing(export_name: &str) -> (RunTimeError, String)
+    {
+        (RunTimeError::FunctionNotSupported, format!("{} is not available in this thinBasic version", export_name))
+    }
+
     #[allow(dead_code)]
     pub enum ReturnType
     {
@@ -90,251 +387,2065 @@ pub mod core
         TBSTR = 30
     }
 
+    // Rust types keyword functions can return to script
+    pub trait KeywordReturn
+    {
+        const RETURN_TYPE: ReturnType;
+
+        // Value returned when the keyword fails before computing its result
+        fn none() -> Self;
+    }
+
+    macro_rules! impl_keyword_return {
+        ($($t:ty => $return_type:ident),*) => {
+            $(
+                impl KeywordReturn for $t
+                {
+                    const RETURN_TYPE: ReturnType = ReturnType::$return_type;
+
+                    fn none() -> Self {
+                        0 as $t
+                    }
+                }
+            )*
+        }
+    }
+
+    impl_keyword_return!(u8 => U8, u16 => U16, u32 => U32, i16 => I16, i32 => I32, i64 => I64, f32 => F32, f64 => F64);
+
+    impl KeywordReturn for ()
+    {
+        const RETURN_TYPE: ReturnType = ReturnType::NONE;
+
+        fn none() -> Self {}
+    }
+
+    impl KeywordReturn for TBStr
+    {
+        const RETURN_TYPE: ReturnType = ReturnType::TBSTR;
+
+        fn none() -> Self {
+            TBStr::from("")
+        }
+    }
+
+    // Results of Rust keyword handlers, converted to what the engine receives
+    pub trait IntoKeywordReturn
+    {
+        type Output: KeywordReturn;
+
+        fn into_return(self, engine: Engine) -> Self::Output;
+    }
+
+    macro_rules! impl_into_keyword_return {
+        ($($t:ty),*) => {
+            $(
+                impl IntoKeywordReturn for $t
+                {
+                    type Output = $t;
+
+                    fn into_return(self, _engine: Engine) -> Self::Output {
+                        self
+                    }
+                }
+            )*
+        }
+    }
+
+    impl_into_keyword_return!((), u8, u16, u32, i16, i32, i64, f32, f64, TBStr);
+
+    impl IntoKeywordReturn for String
+    {
+        type Output = TBStr;
+
+        fn into_return(self, _engine: Engine) -> Self::Output {
+            TBStr::from(self.as_str())
+        }
+    }
+
+    // Err is raised as runtime error, unless the engine already reports one
+    impl<T: IntoKeywordReturn> IntoKeywordReturn for Result<T, RunTimeError>
+    {
+        type Output = T::Output;
+
+        fn into_return(self, engine: Engine) -> Self::Output {
+            match self {
+                Ok(value) => value.into_return(engine),
+                Err(error) => {
+                    if error_free(engine) {
+                        raise_runtime_error(engine, error, "Keyword failed");
+                    }
+                    T::Output::none()
+                }
+            }
+        }
+    }
+
     /*
 
      Library setup
 
     */
 
+    // thinCore exports and keyword functions called by the engine use stdcall, so every declaration is extern "system"
     #[allow(dead_code)]
-    pub fn add_function<T>(symbol_name: &str, function_ptr: extern fn() -> T, return_type: ReturnType) -> i32
+    pub fn add_function<T>(_engine: Engine, symbol_name: &str, function_ptr: extern "system" fn() -> T, return_type: ReturnType) -> i32
     {
         unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
 
-            let thinbasic_loadsymbol: libloading::Symbol<unsafe extern fn(symbol_name: TBStr, return_type: i32, function_ptr: extern fn() -> T, force_overwrite: i32) -> i32> = lib.get(b"thinBasic_LoadSymbol").unwrap();
+            let thinbasic_loadsymbol: libloading::Symbol<unsafe extern "system" fn(symbol_name: TBStr, return_type: i32, function_ptr: extern "system" fn() -> T, force_overwrite: i32) -> i32> = lib.get(b"thinBasic_LoadSymbol").unwrap();
+
+            // Module prefix applies, unprefixed alias follows when the module asks for it
+            let names = module::symbol_names(symbol_name);
+            let return_type = return_type as i32;
 
-            thinbasic_loadsymbol(TBStr::from(symbol_name), return_type as i32, function_ptr, 1)
+            // Symbol id of the name, or the engine result of the first name which failed
+            let mut result = thinbasic_loadsymbol(TBStr::from(names[0].as_str()), return_type, function_ptr, 1);
+            for alias in names.iter().skip(1) {
+                let alias_result = thinbasic_loadsymbol(TBStr::from(alias.as_str()), return_type, function_ptr, 1);
+                if result > 0 && alias_result <= 0 {
+                    result = alias_result;
+                }
+            }
+
+            result
         }
     }
 
+    // What add_function_with does when the name is already taken by another function
     #[allow(dead_code)]
-    pub fn add_string_equate(symbol_name: &str, symbol_value: &str) -> i32
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ExistingFunction
     {
-        unsafe
-        {
-            assert!(symbol_name.starts_with("$"));
-            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+        Overwrite,
+        Fail
+    }
 
-            // Placeholder values added as Rust does not support f80
-            let thinbasic_addequate: libloading::Symbol<unsafe extern fn(symbol_name: TBStr, symbol_value: TBStr, placeholderA: i64, placeholderA: i16, equate_type: i32) -> i32> = lib.get(b"thinBasic_AddEquate").unwrap();
+    // Options for add_function_with, defaults match add_function
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct FunctionOptions
+    {
+        pub existing: ExistingFunction,
+        pub aliases: Vec<String>
+    }
 
-            // 0, 0 are placeholder values; 5 should enforce string type of equate
-            thinbasic_addequate(TBStr::from(symbol_name), TBStr::from(symbol_value), 0, 0, 5)
+    impl Default for FunctionOptions
+    {
+        fn default() -> Self {
+            FunctionOptions { existing: ExistingFunction::Overwrite, aliases: Vec::new() }
         }
     }
 
-    /*
+    impl FunctionOptions
+    {
+        pub fn new() -> Self {
+            FunctionOptions::default()
+        }
 
-     Parsing
+        pub fn fail_if_exists(mut self) -> Self {
+            self.existing = ExistingFunction::Fail;
+            self
+        }
 
-    */
+        pub fn overwrite(mut self) -> Self {
+            self.existing = ExistingFunction::Overwrite;
+            self
+        }
+
+        // Additional name the function is registered under
+        pub fn alias(mut self, alias: &str) -> Self {
+            self.aliases.push(String::from(alias));
+            self
+        }
+    }
 
+    // Why the engine refused a symbol, each carries the name which failed
     #[allow(dead_code)]
-    pub fn parse_i16() -> i16
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum RegistrationError
     {
-        unsafe
-        {
-            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_parseinteger: libloading::Symbol<unsafe extern fn(*const i16)> = lib.get(b"thinBasic_ParseInteger").unwrap();
-            let num: i16 = 0;
-            thinbasic_parseinteger(&num);
+        DuplicateSymbol(String),
+        KeywordConflict(String),
+        VariableConflict(String),
+        UdtConflict(String),
+        // Engine lacks the export needed to register it
+        Unsupported(String),
+        Failed(String, i32)
+    }
 
-            num
+    impl RegistrationError
+    {
+        // thinBasic_LoadSymbol returns symbol id when positive, reason of failure otherwise
+        fn from_load_result(symbol_name: &str, result: i32) -> Result<i32, RegistrationError> {
+            let symbol_name = String::from(symbol_name);
+            match result {
+                id if id > 0 => Ok(id),
+                -1 => Err(RegistrationError::DuplicateSymbol(symbol_name)),
+                -2 => Err(RegistrationError::KeywordConflict(symbol_name)),
+                -3 => Err(RegistrationError::VariableConflict(symbol_name)),
+                -4 => Err(RegistrationError::UdtConflict(symbol_name)),
+                _  => Err(RegistrationError::Failed(symbol_name, result))
+            }
+        }
+
+        pub fn symbol_name(&self) -> &str {
+            match *self {
+                RegistrationError::DuplicateSymbol(ref name) |
+                RegistrationError::KeywordConflict(ref name) |
+                RegistrationError::VariableConflict(ref name) |
+                RegistrationError::UdtConflict(ref name) |
+                RegistrationError::Unsupported(ref name) |
+                RegistrationError::Failed(ref name, _) => name
+            }
+        }
+    }
+
+    impl ::std::fmt::Display for RegistrationError
+    {
+        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+            match *self {
+                RegistrationError::DuplicateSymbol(ref name)  => write!(f, "{} is already defined", name),
+                RegistrationError::KeywordConflict(ref name)  => write!(f, "{} is a keyword", name),
+                RegistrationError::VariableConflict(ref name) => write!(f, "{} is a variable", name),
+                RegistrationError::UdtConflict(ref name)      => write!(f, "{} is a TYPE", name),
+                RegistrationError::Unsupported(ref name)      => write!(f, "{} needs newer thinBasic", name),
+                RegistrationError::Failed(ref name, code)     => write!(f, "{} could not be added ({})", name, code)
+            }
         }
     }
 
+    // Kinds of names the engine knows
     #[allow(dead_code)]
-    pub fn parse_i32() -> i32
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SymbolKind
     {
-        unsafe         
-        {
-            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_parselong: libloading::Symbol<unsafe extern fn(*const i32)> = lib.get(b"thinBasic_ParseLong").unwrap();
-            let num: i32 = 0;
-            thinbasic_parselong(&num);
+        CoreKeyword,
+        ModuleKeyword,
+        ScriptFunction,
+        Equate,
+        Udt,
+        Class,
+        Variable,
+        Other(i32)
+    }
 
-            num
+    impl SymbolKind
+    {
+        fn from_code(code: i32) -> Option<SymbolKind> {
+            match code {
+                0 => None,
+                1 => Some(SymbolKind::CoreKeyword),
+                2 => Some(SymbolKind::ModuleKeyword),
+                3 => Some(SymbolKind::ScriptFunction),
+                4 => Some(SymbolKind::Equate),
+                5 => Some(SymbolKind::Udt),
+                6 => Some(SymbolKind::Class),
+                7 => Some(SymbolKind::Variable),
+                _ => Some(SymbolKind::Other(code))
+            }
         }
     }
 
+    // Symbol found by find_symbol, module is known for module keywords, equates, TYPEs and classes
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct SymbolInfo
+    {
+        pub kind: SymbolKind,
+        pub module: Option<String>
+    }
+
+    // Looks up any symbol by the name engine knows it under, Unsupported on engine without thinBasic_SymbolGetInfo
     #[allow(dead_code)]
-    pub fn parse_i64() -> i64
+    pub fn find_symbol(_engine: Engine, symbol_name: &str) -> Result<Option<SymbolInfo>, RegistrationError>
     {
-        unsafe         
+        unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_parsequad: libloading::Symbol<unsafe extern fn(*const i64)> = lib.get(b"thinBasic_ParseQuad").unwrap();
-            let num: i64 = 0;
-            thinbasic_parsequad(&num);
+            let thinbasic_symbolgetinfo: libloading::Symbol<unsafe extern "system" fn(symbol_name: TBStr, module_name: *mut TBStr) -> i32> = match lib.get(b"thinBasic_SymbolGetInfo") {
+                Ok(thinbasic_symbolgetinfo) => thinbasic_symbolgetinfo,
+                Err(_) => return Err(RegistrationError::Unsupported(String::from(symbol_name)))
+            };
+
+            let mut module_name = TBStr(::std::ptr::null());
+            let kind = match SymbolKind::from_code(thinbasic_symbolgetinfo(TBStr::from(symbol_name), &mut module_name)) {
+                Some(kind) => kind,
+                None => return Ok(None)
+            };
+
+            let module = if module_name.0.is_null() || module_name.len() == 0 { None } else { Some(module_name.to_string()) };
+            Ok(Some(SymbolInfo { kind, module }))
+        }
+    }
 
-            num
+    // Looks up symbol of this module, module prefix applies as when it was added
+    #[allow(dead_code)]
+    pub fn find_symbol_own(engine: Engine, symbol_name: &str) -> Result<Option<SymbolInfo>, RegistrationError>
+    {
+        find_symbol(engine, &module::symbol_name(symbol_name))
+    }
+
+    #[allow(dead_code)]
+    pub fn symbol_exists(engine: Engine, symbol_name: &str) -> Result<bool, RegistrationError>
+    {
+        find_symbol(engine, symbol_name).map(|symbol| symbol.is_some())
+    }
+
+    // Module prefix applies as in find_symbol_own
+    #[allow(dead_code)]
+    pub fn symbol_exists_own(engine: Engine, symbol_name: &str) -> Result<bool, RegistrationError>
+    {
+        find_symbol_own(engine, symbol_name).map(|symbol| symbol.is_some())
+    }
+
+    // Conflict the name would cause, module keywords only conflict when they must not be overwritten
+    fn registration_conflict(engine: Engine, symbol_name: &str, existing: ExistingFunction) -> Result<(), RegistrationError>
+    {
+        let name = String::from(symbol_name);
+
+        match find_symbol(engine, symbol_name) {
+            Ok(Some(symbol)) => match symbol.kind {
+                SymbolKind::ModuleKeyword if existing == ExistingFunction::Fail => Err(RegistrationError::DuplicateSymbol(name)),
+                SymbolKind::CoreKeyword | SymbolKind::ScriptFunction => Err(RegistrationError::KeywordConflict(name)),
+                SymbolKind::Variable => Err(RegistrationError::VariableConflict(name)),
+                SymbolKind::Udt | SymbolKind::Class => Err(RegistrationError::UdtConflict(name)),
+                _ => Ok(())
+            },
+            Ok(None) => Ok(()),
+            // Engine which cannot tell is left to refuse the name in thinBasic_LoadSymbol
+            Err(RegistrationError::Unsupported(_)) => Ok(()),
+            Err(error) => Err(error)
         }
     }
 
+    // Names add_function_with registers, first is the prefixed symbol name and aliases get module prefix too.
+    // Unprefixed aliases never overwrite, they could replace keyword of other module with the same plain name.
+    fn registration_names(symbol_name: &str, options: &FunctionOptions) -> Vec<(String, ExistingFunction)>
+    {
+        ::std::iter::once(symbol_name)
+            .chain(options.aliases.iter().map(|alias| alias.as_str()))
+            .flat_map(|name| {
+                module::symbol_names(name).into_iter().enumerate().map(|(index, name)| {
+                    (name, if index == 0 { options.existing } else { ExistingFunction::Fail })
+                })
+            })
+            .collect()
+    }
+
+    // Registers function under its name and aliases, returns symbol id of the name
     #[allow(dead_code)]
-    pub fn parse_u8() -> u8
+    pub fn add_function_with<T>(engine: Engine, symbol_name: &str, function_ptr: extern "system" fn() -> T, return_type: ReturnType, options: &FunctionOptions) -> Result<i32, RegistrationError>
     {
         unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_parsebyte: libloading::Symbol<unsafe extern fn(*const u8)> = lib.get(b"thinBasic_ParseByte").unwrap();
-            let num: u8 = 0;
-            thinbasic_parsebyte(&num);
 
-            num
+            let thinbasic_loadsymbol: libloading::Symbol<unsafe extern "system" fn(symbol_name: TBStr, return_type: i32, function_ptr: extern "system" fn() -> T, force_overwrite: i32) -> i32> = lib.get(b"thinBasic_LoadSymbol").unwrap();
+
+            let return_type = return_type as i32;
+            let names = registration_names(symbol_name, options);
+
+            // Checked up front, so failing alias does not leave the name half registered
+            for (name, existing) in names.iter() {
+                registration_conflict(engine, name, *existing)?;
+            }
+
+            let mut id = 0;
+            for (name, existing) in names.iter() {
+                let force_overwrite = if *existing == ExistingFunction::Overwrite { 1 } else { 0 };
+                let result = RegistrationError::from_load_result(name, thinbasic_loadsymbol(TBStr::from(name.as_str()), return_type, function_ptr, force_overwrite))?;
+                if id == 0 {
+                    id = result;
+                }
+            }
+
+            Ok(id)
         }
     }
 
     #[allow(dead_code)]
-    pub fn parse_u16() -> u16
+    pub fn add_string_equate(_engine: Engine, symbol_name: &str, symbol_value: &str) -> i32
     {
         unsafe
         {
+            assert!(symbol_name.starts_with("$"));
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_parseword: libloading::Symbol<unsafe extern fn(*const u16)> = lib.get(b"thinBasic_ParseWord").unwrap();
-            let num: u16 = 0;
-            thinbasic_parseword(&num);
 
-            num
+            // Placeholder values added as Rust does not support f80
+            let thinbasic_addequate: libloading::Symbol<unsafe extern "system" fn(symbol_name: TBStr, symbol_value: TBStr, placeholderA: i64, placeholderA: i16, equate_type: i32) -> i32> = lib.get(b"thinBasic_AddEquate").unwrap();
+
+            // 0, 0 are placeholder values; 5 should enforce string type of equate
+            let names = module::symbol_names(symbol_name);
+
+            // Same as add_function, failing alias is reported instead of the name result
+            let mut result = thinbasic_addequate(TBStr::from(names[0].as_str()), TBStr::from(symbol_value), 0, 0, 5);
+            for alias in names.iter().skip(1) {
+                let alias_result = thinbasic_addequate(TBStr::from(alias.as_str()), TBStr::from(symbol_value), 0, 0, 5);
+                if result > 0 && alias_result <= 0 {
+                    result = alias_result;
+                }
+            }
+
+            result
         }
     }
 
+    /*
+
+     Equates
+
+    */
+
+    // Equate types understood by thinBasic_AddEquate
+    const EQUATE_TYPE_NUMBER: i32 = 4;
+    const EQUATE_TYPE_STRING: i32 = 5;
+
+    // Value of $ or % equate
     #[allow(dead_code)]
-    pub fn parse_u32() -> u32
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum EquateValue
     {
-        unsafe
-        {
-            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_parsedword: libloading::Symbol<unsafe extern fn(*const u32)> = lib.get(b"thinBasic_ParseDWord").unwrap();
-            let num: u32 = 0;
-            thinbasic_parsedword(&num);
+        Number(f64),
+        Text(String)
+    }
+
+    impl EquateValue
+    {
+        // String equates are named with $, numeric ones with %
+        fn matches_name(&self, symbol_name: &str) -> bool {
+            match *self {
+                EquateValue::Number(_) => symbol_name.starts_with('%'),
+                EquateValue::Text(_)   => symbol_name.starts_with('$')
+            }
+        }
+
+        // Number goes as 80-bit float split in two, Rust has no f80 to pass by value
+        fn to_engine(&self) -> (TBStr, i64, i16, i32) {
+            match *self {
+                EquateValue::Number(number) => {
+                    let ext = Ext::from_f64(number);
+                    let bytes = ext.0;
+                    let mut low = [0u8; 8];
+                    low.copy_from_slice(&bytes[0..8]);
+
+                    (TBStr::from(""), i64::from_le_bytes(low), i16::from_le_bytes([bytes[8], bytes[9]]), EQUATE_TYPE_NUMBER)
+                }
+                EquateValue::Text(ref text) => (TBStr::from(text.as_str()), 0, 0, EQUATE_TYPE_STRING)
+            }
+        }
+    }
+
+    // Value of any equate by the name engine knows it under, None also on engine without thinBasic_EquateGetValue
+    #[allow(dead_code)]
+    pub fn equate_value(_engine: Engine, symbol_name: &str) -> Option<EquateValue>
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_equategetvalue: libloading::Symbol<unsafe extern "system" fn(symbol_name: TBStr, text_value: *mut TBStr, number_value: *mut Ext) -> i32> = lib.get(b"thinBasic_EquateGetValue").ok()?;
+
+            let mut text = TBStr(::std::ptr::null());
+            let mut number = Ext::default();
+
+            match thinbasic_equategetvalue(TBStr::from(symbol_name), &mut text, &mut number) {
+                EQUATE_TYPE_STRING => Some(EquateValue::Text(if text.0.is_null() { String::new() } else { text.to_string() })),
+                EQUATE_TYPE_NUMBER => Some(EquateValue::Number(number.to_f64())),
+                _ => None
+            }
+        }
+    }
+
+    // Value of equate this module added, module prefix applies as in add_equate
+    #[allow(dead_code)]
+    pub fn equate_value_own(engine: Engine, symbol_name: &str) -> Option<EquateValue>
+    {
+        equate_value(engine, &module::symbol_name(symbol_name))
+    }
+
+    // Existing equate is reported as EquateAlreadyDefined when it holds the same value, EquateAlreadyDefinedDifferent otherwise
+    #[allow(dead_code)]
+    pub fn add_equate(engine: Engine, symbol_name: &str, value: &EquateValue) -> Result<(), RunTimeError>
+    {
+        if !value.matches_name(symbol_name) {
+            return Err(RunTimeError::InvalidDataType);
+        }
+
+        // Every name is checked first, so taken alias does not leave the equate half added
+        let names = module::symbol_names(symbol_name);
+        for name in names.iter() {
+            check_equate_free(engine, name, value)?;
+        }
+
+        for (index, name) in names.iter().enumerate() {
+            if let Err(error) = add_equate_name(engine, name, value) {
+                for added in names[..index].iter() {
+                    remove_equate_exact(engine, added);
+                }
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_equate_free(engine: Engine, symbol_name: &str, value: &EquateValue) -> Result<(), RunTimeError>
+    {
+        match equate_value(engine, symbol_name) {
+            Some(ref existing) if existing == value => Err(RunTimeError::EquateAlreadyDefined),
+            Some(_) => Err(RunTimeError::EquateAlreadyDefinedDifferent),
+            None => Ok(())
+        }
+    }
+
+    fn add_equate_name(engine: Engine, symbol_name: &str, value: &EquateValue) -> Result<(), RunTimeError>
+    {
+        check_equate_free(engine, symbol_name, value)?;
+
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_addequate: libloading::Symbol<unsafe extern "system" fn(symbol_name: TBStr, symbol_value: TBStr, number_low: i64, number_high: i16, equate_type: i32) -> i32> = lib.get(b"thinBasic_AddEquate").unwrap();
+
+            // No equate of that name, so engine refused symbol of other kind
+            let (text, number_low, number_high, equate_type) = value.to_engine();
+            if thinbasic_addequate(TBStr::from(symbol_name), text, number_low, number_high, equate_type) > 0 {
+                Ok(())
+            } else {
+                Err(RunTimeError::DuplicateSymbol)
+            }
+        }
+    }
+
+    // Adds equate or changes value of the one module added before
+    #[allow(dead_code)]
+    pub fn set_equate(engine: Engine, symbol_name: &str, value: &EquateValue) -> Result<(), RunTimeError>
+    {
+        if !value.matches_name(symbol_name) {
+            return Err(RunTimeError::InvalidDataType);
+        }
+
+        // Failing alias puts back what the names held before, as add_equate does
+        let names = module::symbol_names(symbol_name);
+        let previous: Vec<Option<EquateValue>> = names.iter().map(|name| equate_value(engine, name)).collect();
+
+        for (index, name) in names.iter().enumerate() {
+            if let Err(error) = set_equate_name(engine, name, value) {
+                for (changed, previous_value) in names[..index].iter().zip(previous.iter()) {
+                    match *previous_value {
+                        Some(ref previous_value) => { let _ = set_equate_name(engine, changed, previous_value); }
+                        None => { remove_equate_exact(engine, changed); }
+                    }
+                }
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_equate_name(engine: Engine, symbol_name: &str, value: &EquateValue) -> Result<(), RunTimeError>
+    {
+        if equate_value(engine, symbol_name).is_none() {
+            return add_equate_name(engine, symbol_name, value);
+        }
+
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_equatesetvalue: libloading::Symbol<unsafe extern "system" fn(symbol_name: TBStr, symbol_value: TBStr, number_low: i64, number_high: i16, equate_type: i32) -> i32> = optional_export(engine, &lib, "thinBasic_EquateSetValue")?;
+
+            // Engine refuses equates owned by script or other modules
+            let (text, number_low, number_high, equate_type) = value.to_engine();
+            if thinbasic_equatesetvalue(TBStr::from(symbol_name), text, number_low, number_high, equate_type) > 0 {
+                Ok(())
+            } else {
+                Err(RunTimeError::EquateAlreadyDefinedDifferent)
+            }
+        }
+    }
+
+    // Removes equate module added before, false when it does not exist or belongs to someone else
+    #[allow(dead_code)]
+    pub fn remove_equate(engine: Engine, symbol_name: &str) -> bool
+    {
+        let removed: Vec<bool> = module::symbol_names(symbol_name).iter().map(|name| remove_equate_exact(engine, name)).collect();
+        removed[0]
+    }
+
+    fn remove_equate_exact(engine: Engine, symbol_name: &str) -> bool
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_equateremove: libloading::Symbol<unsafe extern "system" fn(symbol_name: TBStr) -> i32> = match optional_export(engine, &lib, "thinBasic_EquateRemove") {
+                Ok(thinbasic_equateremove) => thinbasic_equateremove,
+                Err(_) => return false
+            };
+
+            thinbasic_equateremove(TBStr::from(symbol_name)) > 0
+        }
+    }
+
+    /*
+
+     Parsing
+
+    */
+
+    #[allow(dead_code)]
+    pub fn parse_i16(_engine: Engine) -> i16
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_parseinteger: libloading::Symbol<unsafe extern "system" fn(*const i16)> = lib.get(b"thinBasic_ParseInteger").unwrap();
+            let num: i16 = 0;
+            thinbasic_parseinteger(&num);
+
+            num
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn parse_i32(_engine: Engine) -> i32
+    {
+        unsafe         
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_parselong: libloading::Symbol<unsafe extern "system" fn(*const i32)> = lib.get(b"thinBasic_ParseLong").unwrap();
+            let num: i32 = 0;
+            thinbasic_parselong(&num);
+
+            num
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn parse_i64(_engine: Engine) -> i64
+    {
+        unsafe         
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_parsequad: libloading::Symbol<unsafe extern "system" fn(*const i64)> = lib.get(b"thinBasic_ParseQuad").unwrap();
+            let num: i64 = 0;
+            thinbasic_parsequad(&num);
+
+            num
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn parse_u8(_engine: Engine) -> u8
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_parsebyte: libloading::Symbol<unsafe extern "system" fn(*const u8)> = lib.get(b"thinBasic_ParseByte").unwrap();
+            let num: u8 = 0;
+            thinbasic_parsebyte(&num);
+
+            num
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn parse_u16(_engine: Engine) -> u16
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_parseword: libloading::Symbol<unsafe extern "system" fn(*const u16)> = lib.get(b"thinBasic_ParseWord").unwrap();
+            let num: u16 = 0;
+            thinbasic_parseword(&num);
+
+            num
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn parse_u32(_engine: Engine) -> u32
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_parsedword: libloading::Symbol<unsafe extern "system" fn(*const u32)> = lib.get(b"thinBasic_ParseDWord").unwrap();
+            let num: u32 = 0;
+            thinbasic_parsedword(&num);
+
+            num
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn parse_f32(_engine: Engine) -> f32
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_parsesingle: libloading::Symbol<unsafe extern "system" fn(*const f32)> = lib.get(b"thinBasic_ParseSingle").unwrap();
+            let num: f32 = 0.0;
+            thinbasic_parsesingle(&num);
+
+            num
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn parse_f64(_engine: Engine) -> f64
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_parsedouble: libloading::Symbol<unsafe extern "system" fn(*const f64)> = lib.get(b"thinBasic_ParseDouble").unwrap();
+            let num: f64 = 0.0;
+            thinbasic_parsedouble(&num);
+
+            num
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn parse_tbstr(_engine: Engine) -> TBStr
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_parsestr: libloading::Symbol<unsafe extern "system" fn(*const TBStr)> = lib.get(b"thinBasic_ParseStr").unwrap();
+            let text: TBStr = TBStr::from(" ");
+            thinbasic_parsestr(&text);
+
+            text
+        }
+    }
+
+    // Rust types which can be parsed as keyword argument
+    pub trait ParseArg: Sized
+    {
+        fn parse(engine: Engine) -> Self;
+    }
+
+    macro_rules! impl_parse_arg {
+        ($($t:ty => $parse:ident),*) => {
+            $(
+                impl ParseArg for $t
+                {
+                    fn parse(engine: Engine) -> Self {
+                        $parse(engine)
+                    }
+                }
+            )*
+        }
+    }
+
+    impl_parse_arg!(u8 => parse_u8, u16 => parse_u16, u32 => parse_u32, i16 => parse_i16, i32 => parse_i32, i64 => parse_i64, f32 => parse_f32, f64 => parse_f64, TBStr => parse_tbstr);
+
+    impl ParseArg for String
+    {
+        fn parse(engine: Engine) -> Self {
+            parse_tbstr(engine).to_string()
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn check_comma(_engine: Engine) -> bool
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_checkcomma_mandatory: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_CheckComma_Mandatory").unwrap();
+            let result = thinbasic_checkcomma_mandatory();
+
+            return if result == 0 { false } else { true }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn check_comma_optional(_engine: Engine) -> bool
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_checkcomma_optional: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_CheckComma_Optional").unwrap();
+            let result = thinbasic_checkcomma_optional();
+
+            return if result == 0 { false } else { true }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn check_open_parens(_engine: Engine) -> bool
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_checkopenparens_mandatory: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_CheckOpenParens_Mandatory").unwrap();
+            let result = thinbasic_checkopenparens_mandatory();
+
+            return if result == 0 { false } else { true }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn check_open_parens_optional(_engine: Engine) -> bool
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_checkopenparens_optional: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_CheckOpenParens_Optional").unwrap();
+            let result = thinbasic_checkopenparens_optional();
+
+            return if result == 0 { false } else { true }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn check_close_parens(_engine: Engine) -> bool
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_checkcloseparens_mandatory: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_CheckCloseParens_Mandatory").unwrap();
+            let result = thinbasic_checkcloseparens_mandatory();
+
+            return if result == 0 { false } else { true }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn check_close_parens_optional(_engine: Engine) -> bool
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_checkcloseparens_optional: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_CheckCloseParens_Optional").unwrap();
+            let result = thinbasic_checkcloseparens_optional();
+
+            return if result == 0 { false } else { true }
+        }
+    }   
+
+    /*
+
+     Token checks
+
+    */
+
+    // Punctuation and keywords a keyword syntax can require at the current parsing position
+    #[allow(dead_code)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Token
+    {
+        Comma,
+        OpenParens,
+        CloseParens,
+        OpenSquareParens,
+        CloseSquareParens,
+        Equal,
+        Semicolon,
+        Hash,
+        To,
+        Step,
+        As,
+        In,
+        Using,
+        Keyword(&'static str)
+    }
+
+    impl Token
+    {
+        // Text as written in script, used in error descriptions
+        pub fn text(&self) -> &'static str {
+            match *self {
+                Token::Comma             => ",",
+                Token::OpenParens        => "(",
+                Token::CloseParens       => ")",
+                Token::OpenSquareParens  => "[",
+                Token::CloseSquareParens => "]",
+                Token::Equal             => "=",
+                Token::Semicolon         => ";",
+                Token::Hash              => "#",
+                Token::To                => "TO",
+                Token::Step              => "STEP",
+                Token::As                => "AS",
+                Token::In                => "IN",
+                Token::Using             => "USING",
+                Token::Keyword(keyword)  => keyword
+            }
+        }
+
+        // Error reported when mandatory token is missing
+        pub fn missing_error(&self) -> RunTimeError {
+            match *self {
+                Token::Comma                     => RunTimeError::MissingComma,
+                Token::OpenParens                => RunTimeError::MissingOpenparens,
+                Token::OpenSquareParens          => RunTimeError::MissingOpenparens,
+                Token::CloseParens               => RunTimeError::MissingCloseparens,
+                Token::CloseSquareParens         => RunTimeError::MissingSquarecloseparens,
+                Token::Equal                     => RunTimeError::EqualExp,
+                Token::Semicolon                 => RunTimeError::MissingSemicolon,
+                Token::To                        => RunTimeError::ToExpected,
+                Token::Hash | Token::Step | Token::As | Token::In | Token::Using | Token::Keyword(_) => RunTimeError::TokenNotExpected
+            }
+        }
+
+        // Punctuation has dedicated engine checks, keywords share one
+        fn check_export(&self) -> Option<&'static str> {
+            match *self {
+                Token::Comma             => Some("thinBasic_CheckComma"),
+                Token::OpenParens        => Some("thinBasic_CheckOpenParens"),
+                Token::CloseParens       => Some("thinBasic_CheckCloseParens"),
+                Token::OpenSquareParens  => Some("thinBasic_CheckOpenSquareParens"),
+                Token::CloseSquareParens => Some("thinBasic_CheckCloseSquareParens"),
+                Token::Equal             => Some("thinBasic_CheckEqual"),
+                Token::Semicolon         => Some("thinBasic_CheckSemicolon"),
+                Token::Hash              => Some("thinBasic_CheckHash"),
+                _ => None
+            }
+        }
+    }
+
+    // Consumes token when present, always the optional form of the engine check
+    unsafe fn check_token(engine: Engine, token: Token) -> bool
+    {
+        let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+
+        let result = match token.check_export() {
+            Some(export) => {
+                let export_name = format!("{}_Optional", export);
+                let thinbasic_check_optional: libloading::Symbol<unsafe extern "system" fn() -> i32> = match optional_export(engine, &lib, &export_name) {
+                    Ok(thinbasic_check_optional) => thinbasic_check_optional,
+                    Err(_) => return false
+                };
+
+                thinbasic_check_optional()
+            }
+            None => {
+                let thinbasic_checkkeyword_optional: libloading::Symbol<unsafe extern "system" fn(keyword: TBStr) -> i32> = match optional_export(engine, &lib, "thinBasic_CheckKeyword_Optional") {
+                    Ok(thinbasic_checkkeyword_optional) => thinbasic_checkkeyword_optional,
+                    Err(_) => return false
+                };
+
+                thinbasic_checkkeyword_optional(TBStr::from(token.text()))
+            }
+        };
+
+        result != 0
+    }
+
+    // Optional token, true when it was found and consumed
+    #[allow(dead_code)]
+    pub fn accept_token(engine: Engine, token: Token) -> bool
+    {
+        unsafe
+        {
+            check_token(engine, token)
+        }
+    }
+
+    // Mandatory token, raises the matching runtime error when it is missing
+    #[allow(dead_code)]
+    pub fn expect_token(engine: Engine, token: Token) -> Result<(), RunTimeError>
+    {
+        if accept_token(engine, token) {
+            return Ok(());
+        }
+
+        let error = token.missing_error();
+        if error_free(engine) {
+            raise_runtime_error(engine, error, &format!("{} expected", token.text()));
+        }
+
+        Err(error)
+    }
+
+    // One of alternative tokens is mandatory, returns index of the one found
+    #[allow(dead_code)]
+    pub fn expect_choice(engine: Engine, choices: &[Token]) -> Result<usize, RunTimeError>
+    {
+        if let Some(index) = choices.iter().position(|&token| accept_token(engine, token)) {
+            return Ok(index);
+        }
+
+        let expected: Vec<&str> = choices.iter().map(|token| token.text()).collect();
+        if error_free(engine) {
+            raise_runtime_error(engine, RunTimeError::TokenNotExpected, &format!("One of {} expected", expected.join(", ")));
+        }
+
+        Err(RunTimeError::TokenNotExpected)
+    }
+
+
+    /*
+
+     Token peeking
+
+    */
+
+    // Classification of the token at the current parsing position
+    #[allow(dead_code)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum PeekedToken
+    {
+        NumericLiteral(String),
+        StringLiteral(String),
+        Variable(String, VariableType),
+        Equate(String),
+        Keyword(String),
+        Punctuation(String),
+        EndOfLine,
+        Other(String)
+    }
+
+    impl PeekedToken
+    {
+        fn from_class(token_class: i32, text: String, main_type: i32) -> PeekedToken {
+            match token_class {
+                0 => PeekedToken::EndOfLine,
+                1 => PeekedToken::NumericLiteral(text),
+                2 => PeekedToken::StringLiteral(text),
+                3 => PeekedToken::Variable(text, VariableType::from_main_type(main_type)),
+                4 => PeekedToken::Equate(text),
+                5 => PeekedToken::Keyword(text),
+                6 => PeekedToken::Punctuation(text),
+                _ => PeekedToken::Other(text)
+            }
+        }
+
+        // Token starts string expression, string equates are named with $ and string functions end with it
+        pub fn is_string(&self) -> bool {
+            match *self {
+                PeekedToken::StringLiteral(_)                     => true,
+                PeekedToken::Variable(_, VariableType::TBSTR)     => true,
+                PeekedToken::Equate(ref name)                     => name.starts_with('$'),
+                PeekedToken::Keyword(ref name)                    => name.ends_with('$'),
+                _ => false
+            }
+        }
+
+        // Token starts numeric expression
+        pub fn is_numeric(&self) -> bool {
+            match *self {
+                PeekedToken::NumericLiteral(_)                    => true,
+                PeekedToken::Variable(_, ref var_type)            => var_type.is_numeric(),
+                PeekedToken::Equate(ref name)                     => !name.starts_with('$'),
+                _ => false
+            }
+        }
+
+        // Punctuation like ")" or ","
+        pub fn is_punctuation(&self, punctuation: &str) -> bool {
+            match *self {
+                PeekedToken::Punctuation(ref text) => text == punctuation,
+                _ => false
+            }
+        }
+    }
+
+    // Looks at the next token without consuming it, Other with runtime error raised on engine without thinBasic_TokenPeek
+    #[allow(dead_code)]
+    pub fn peek_token(engine: Engine) -> PeekedToken
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_tokenpeek: libloading::Symbol<unsafe extern "system" fn(token_text: *mut TBStr, main_type: *mut i32) -> i32> = match optional_export(engine, &lib, "thinBasic_TokenPeek") {
+                Ok(thinbasic_tokenpeek) => thinbasic_tokenpeek,
+                Err(_) => return PeekedToken::Other(String::new())
+            };
+
+            let mut text = TBStr(::std::ptr::null());
+            let mut main_type: i32 = 0;
+            let token_class = thinbasic_tokenpeek(&mut text, &mut main_type);
+
+            let text = if text.0.is_null() { String::new() } else { text.to_string() };
+            PeekedToken::from_class(token_class, text, main_type)
+        }
+    }
+
+
+    /*
+
+     Batched parsing
+
+    */
+
+    // thinCore multi-number parsers work with 80-bit extended floats
+    const PARSE_X_NUMBERS_MAX: usize = 10;
+
+    #[repr(C, packed)]
+    #[derive(Clone, Copy, Default)]
+    pub struct Ext([u8; 10]);
+
+    impl Ext
+    {
+        pub fn to_f64(&self) -> f64 {
+            let mut mantissa_bytes = [0u8; 8];
+            mantissa_bytes.copy_from_slice(&self.0[0..8]);
+            let mantissa = u64::from_le_bytes(mantissa_bytes);
+            let sign_exponent = u16::from_le_bytes([self.0[8], self.0[9]]);
+
+            let sign = if sign_exponent & 0x8000 != 0 { -1.0 } else { 1.0 };
+            let exponent = (sign_exponent & 0x7FFF) as i32;
+
+            if exponent == 0x7FFF {
+                return if mantissa << 1 == 0 { sign * f64::INFINITY } else { f64::NAN };
+            }
+            if mantissa == 0 {
+                return sign * 0.0;
+            }
+
+            // Explicit integer bit, so value = mantissa * 2^(exponent - bias - 63)
+            let mut value = mantissa as f64;
+            let mut scale = exponent - 16383 - 63;
+            while scale > 0 {
+                let step = scale.min(1000);
+                value *= 2f64.powi(step);
+                scale -= step;
+            }
+            while scale < 0 {
+                let step = scale.max(-1000);
+                value *= 2f64.powi(step);
+                scale -= step;
+            }
+
+            sign * value
+        }
+
+        pub fn from_f64(value: f64) -> Ext {
+            let bits = value.to_bits();
+            let sign: u16 = if bits >> 63 != 0 { 0x8000 } else { 0 };
+            let exponent = ((bits >> 52) & 0x7FF) as i32;
+            let fraction = bits & 0x000F_FFFF_FFFF_FFFF;
+
+            let (mantissa, ext_exponent): (u64, i32) = if exponent == 0x7FF {
+                // Infinity keeps just the integer bit, NaN gets a quiet payload
+                (if fraction == 0 { 1 << 63 } else { 0xC000_0000_0000_0000 }, 0x7FFF)
+            } else if exponent == 0 && fraction == 0 {
+                (0, 0)
+            } else if exponent == 0 {
+                // Subnormal doubles are normal numbers in extended precision
+                let shift = fraction.leading_zeros() as i32;
+                (fraction << shift, 16383 + 63 - 1074 - shift)
+            } else {
+                (((1u64 << 52) | fraction) << 11, exponent - 1023 + 16383)
+            };
+
+            let mut bytes = [0u8; 10];
+            bytes[0..8].copy_from_slice(&mantissa.to_le_bytes());
+            bytes[8..10].copy_from_slice(&(sign | ext_exponent as u16).to_le_bytes());
+
+            Ext(bytes)
+        }
+    }
+
+    type ParseXNumbersFn = unsafe extern "system" fn(min_count: i32, max_count: i32,
+                                                     n1: *mut Ext, n2: *mut Ext, n3: *mut Ext, n4: *mut Ext, n5: *mut Ext,
+                                                     n6: *mut Ext, n7: *mut Ext, n8: *mut Ext, n9: *mut Ext, n10: *mut Ext) -> i32;
+
+    // Fills up to 10 values with one engine call, returns how many numbers were parsed
+    unsafe fn parse_x_numbers(thinbasic_parsexnumbers: &ParseXNumbersFn, min_count: usize, values: &mut [f64]) -> usize
+    {
+        let mut ext = [Ext::default(); PARSE_X_NUMBERS_MAX];
+        let count = values.len().min(PARSE_X_NUMBERS_MAX);
+
+        let mut ptrs = [::std::ptr::null_mut::<Ext>(); PARSE_X_NUMBERS_MAX];
+        for (ptr, slot) in ptrs.iter_mut().zip(ext.iter_mut()).take(count) {
+            *ptr = slot;
+        }
+
+        let parsed = thinbasic_parsexnumbers(min_count as i32, count as i32,
+                                             ptrs[0], ptrs[1], ptrs[2], ptrs[3], ptrs[4],
+                                             ptrs[5], ptrs[6], ptrs[7], ptrs[8], ptrs[9]);
+        let parsed = (parsed.max(0) as usize).min(count);
+
+        for (value, slot) in values.iter_mut().zip(ext.iter()).take(parsed) {
+            *value = slot.to_f64();
+        }
+
+        parsed
+    }
+
+    // Parses exactly N comma separated numbers
+    #[allow(dead_code)]
+    pub fn parse_numbers<const N: usize>(_engine: Engine) -> [f64; N]
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let mut values = [0.0f64; N];
+
+            match lib.get::<ParseXNumbersFn>(b"thinBasic_ParseXNumbers") {
+                Ok(thinbasic_parsexnumbers) => {
+                    let thinbasic_checkcomma_mandatory: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_CheckComma_Mandatory").unwrap();
+
+                    let mut offset = 0;
+                    while offset < N {
+                        if offset > 0 && thinbasic_checkcomma_mandatory() == 0 {
+                            break;
+                        }
+
+                        let chunk = &mut values[offset..(offset + PARSE_X_NUMBERS_MAX).min(N)];
+                        let chunk_len = chunk.len();
+                        if parse_x_numbers(&thinbasic_parsexnumbers, chunk_len, chunk) < chunk_len {
+                            break;
+                        }
+                        offset += chunk_len;
+                    }
+                }
+
+                // Older engines, one call per number and comma, but still just one library load
+                Err(_) => {
+                    let thinbasic_parsedouble: libloading::Symbol<unsafe extern "system" fn(*mut f64)> = lib.get(b"thinBasic_ParseDouble").unwrap();
+                    let thinbasic_checkcomma_mandatory: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_CheckComma_Mandatory").unwrap();
+
+                    for (i, value) in values.iter_mut().enumerate() {
+                        if i > 0 && thinbasic_checkcomma_mandatory() == 0 {
+                            break;
+                        }
+                        thinbasic_parsedouble(value);
+                    }
+                }
+            }
+
+            values
+        }
+    }
+
+    // Parses one or more comma separated numbers, as many as the script passes
+    #[allow(dead_code)]
+    pub fn parse_number_list(_engine: Engine) -> Vec<f64>
+    {
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_checkcomma_optional: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_CheckComma_Optional").unwrap();
+            let mut values: Vec<f64> = Vec::new();
+
+            match lib.get::<ParseXNumbersFn>(b"thinBasic_ParseXNumbers") {
+                Ok(thinbasic_parsexnumbers) => {
+                    loop {
+                        let mut chunk = [0.0f64; PARSE_X_NUMBERS_MAX];
+                        let parsed = parse_x_numbers(&thinbasic_parsexnumbers, 1, &mut chunk);
+                        values.extend_from_slice(&chunk[..parsed]);
+
+                        // A full chunk may be followed by more numbers
+                        if parsed < PARSE_X_NUMBERS_MAX || thinbasic_checkcomma_optional() == 0 {
+                            break;
+                        }
+                    }
+                }
+
+                Err(_) => {
+                    let thinbasic_parsedouble: libloading::Symbol<unsafe extern "system" fn(*mut f64)> = lib.get(b"thinBasic_ParseDouble").unwrap();
+
+                    loop {
+                        let mut value: f64 = 0.0;
+                        thinbasic_parsedouble(&mut value);
+                        values.push(value);
+
+                        if thinbasic_checkcomma_optional() == 0 {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            values
+        }
+    }
+
+    /*
+
+     Variables
+
+    */
+
+    // Main types of script variables, numeric codes match ReturnType where they overlap
+    #[allow(dead_code)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum VariableType
+    {
+        U8,
+        I16,
+        U16,
+        U32,
+        I32,
+        I64,
+        F32,
+        F64,
+        EXT,
+        TBSTR,
+        UDT,
+        OTHER(i32)
+    }
+
+    impl VariableType
+    {
+        pub fn from_main_type(main_type: i32) -> VariableType {
+            match main_type {
+                1  => VariableType::U8,
+                2  => VariableType::I16,
+                3  => VariableType::U16,
+                4  => VariableType::U32,
+                5  => VariableType::I32,
+                6  => VariableType::I64,
+                7  => VariableType::F32,
+                8  => VariableType::F64,
+                10 => VariableType::EXT,
+                30 => VariableType::TBSTR,
+                50 => VariableType::UDT,
+                _  => VariableType::OTHER(main_type)
+            }
+        }
+
+        pub fn is_numeric(&self) -> bool {
+            !matches!(*self, VariableType::TBSTR | VariableType::UDT | VariableType::OTHER(_))
+        }
+
+        // Size of one element in engine memory, 0 when not known
+        pub fn element_size(&self) -> usize {
+            match *self {
+                VariableType::U8                                        => 1,
+                VariableType::I16 | VariableType::U16                   => 2,
+                VariableType::I32 | VariableType::U32 | VariableType::F32 => 4,
+                VariableType::I64 | VariableType::F64                   => 8,
+                VariableType::EXT                                       => 10,
+                VariableType::TBSTR                                     => ::std::mem::size_of::<*const u8>(),
+                VariableType::UDT | VariableType::OTHER(_)              => 0
+            }
+        }
+    }
+
+    // Rust types which can be read from and stored to script variables
+    pub trait VariableValue: Sized
+    {
+        fn accepts(var_type: VariableType) -> bool;
+        fn mismatch_error() -> RunTimeError;
+
+        /// # Safety
+        /// data_ptr must point to a live element of var_type in engine memory
+        unsafe fn read(var_type: VariableType, data_ptr: *const u8) -> Self;
+
+        /// # Safety
+        /// Same as read, var_ptr and abs_pos must describe the same element as data_ptr
+        unsafe fn write(engine: Engine, var_ptr: usize, abs_pos: i32, var_type: VariableType, data_ptr: *mut u8, value: Self) -> Result<(), RunTimeError>;
+    }
+
+    macro_rules! impl_numeric_variable_value {
+        ($($t:ty),*) => {
+            $(
+                impl VariableValue for $t
+                {
+                    fn accepts(var_type: VariableType) -> bool {
+                        var_type.is_numeric()
+                    }
+
+                    fn mismatch_error() -> RunTimeError {
+                        RunTimeError::InvalidDataType
+                    }
+
+                    unsafe fn read(var_type: VariableType, data_ptr: *const u8) -> Self {
+                        match var_type {
+                            VariableType::U8  => *data_ptr as $t,
+                            VariableType::I16 => ::std::ptr::read_unaligned(data_ptr as *const i16) as $t,
+                            VariableType::U16 => ::std::ptr::read_unaligned(data_ptr as *const u16) as $t,
+                            VariableType::U32 => ::std::ptr::read_unaligned(data_ptr as *const u32) as $t,
+                            VariableType::I32 => ::std::ptr::read_unaligned(data_ptr as *const i32) as $t,
+                            VariableType::I64 => ::std::ptr::read_unaligned(data_ptr as *const i64) as $t,
+                            VariableType::F32 => ::std::ptr::read_unaligned(data_ptr as *const f32) as $t,
+                            VariableType::F64 => ::std::ptr::read_unaligned(data_ptr as *const f64) as $t,
+                            VariableType::EXT => ::std::ptr::read_unaligned(data_ptr as *const Ext).to_f64() as $t,
+                            _ => <$t>::default()
+                        }
+                    }
+
+                    unsafe fn write(_engine: Engine, _var_ptr: usize, _abs_pos: i32, var_type: VariableType, data_ptr: *mut u8, value: Self) -> Result<(), RunTimeError> {
+                        match var_type {
+                            VariableType::U8  => *data_ptr = value as u8,
+                            VariableType::I16 => ::std::ptr::write_unaligned(data_ptr as *mut i16, value as i16),
+                            VariableType::U16 => ::std::ptr::write_unaligned(data_ptr as *mut u16, value as u16),
+                            VariableType::U32 => ::std::ptr::write_unaligned(data_ptr as *mut u32, value as u32),
+                            VariableType::I32 => ::std::ptr::write_unaligned(data_ptr as *mut i32, value as i32),
+                            VariableType::I64 => ::std::ptr::write_unaligned(data_ptr as *mut i64, value as i64),
+                            VariableType::F32 => ::std::ptr::write_unaligned(data_ptr as *mut f32, value as f32),
+                            VariableType::F64 => ::std::ptr::write_unaligned(data_ptr as *mut f64, value as f64),
+                            VariableType::EXT => ::std::ptr::write_unaligned(data_ptr as *mut Ext, Ext::from_f64(value as f64)),
+                            _ => ()
+                        }
+
+                        Ok(())
+                    }
+                }
+            )*
+        }
+    }
+
+    impl_numeric_variable_value!(u8, i16, u16, u32, i32, i64, f32, f64);
+
+    impl VariableValue for String
+    {
+        fn accepts(var_type: VariableType) -> bool {
+            var_type == VariableType::TBSTR
+        }
+
+        fn mismatch_error() -> RunTimeError {
+            RunTimeError::VariableMustbeStringType
+        }
+
+        unsafe fn read(_var_type: VariableType, data_ptr: *const u8) -> Self {
+            let bstr = ::std::ptr::read_unaligned(data_ptr as *const *const u8);
+            if bstr.is_null() {
+                return String::new();
+            }
+
+            let slice: &[u8] = ::std::slice::from_raw_parts(bstr, SysStringByteLen(bstr) as usize);
+            string_from_ansi(slice)
+        }
+
+        unsafe fn write(engine: Engine, var_ptr: usize, abs_pos: i32, _var_type: VariableType, _data_ptr: *mut u8, value: Self) -> Result<(), RunTimeError> {
+            // Engine owns the string memory, so let it do the reallocation
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let thinbasic_changevariablestringdirect: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize, abs_pos: i32, value: TBStr) -> i32> = optional_export(engine, &lib, "thinBasic_ChangeVariableStringDirect")?;
+
+            thinbasic_changevariablestringdirect(var_ptr, abs_pos, TBStr::from(value.as_str()));
+            Ok(())
+        }
+    }
+
+    // Variables handed out as VariableRef or ArrayRef, by engine variable pointer.
+    // Element values are only copied, but slices of ArrayRef must not see writes through anything else,
+    // so array borrow is exclusive: -1 while ArrayRef exists, number of VariableRefs otherwise.
+    thread_local! {
+        static BORROWED_VARIABLES: ::std::cell::RefCell<::std::collections::HashMap<usize, isize>> = ::std::cell::RefCell::new(::std::collections::HashMap::new());
+    }
+
+    // Keeps variable borrowed until dropped
+    struct VariableBorrow
+    {
+        var_ptr: usize
+    }
+
+    impl VariableBorrow
+    {
+        // None when the variable is borrowed in conflicting way already
+        fn new(var_ptr: usize, exclusive: bool) -> Option<VariableBorrow> {
+            BORROWED_VARIABLES.with(|borrowed| {
+                let mut borrowed = borrowed.borrow_mut();
+                let count = borrowed.entry(var_ptr).or_insert(0);
+
+                match (*count, exclusive) {
+                    (0, true) => *count = -1,
+                    (count_now, false) if count_now >= 0 => *count += 1,
+                    _ => return None
+                }
+
+                Some(VariableBorrow { var_ptr })
+            })
+        }
+    }
+
+    impl Drop for VariableBorrow
+    {
+        fn drop(&mut self) {
+            // Thread may be already shutting down
+            let _ = BORROWED_VARIABLES.try_with(|borrowed| {
+                let mut borrowed = borrowed.borrow_mut();
+                let remove = match borrowed.get_mut(&self.var_ptr) {
+                    Some(count) if *count > 1 => { *count -= 1; false }
+                    _ => true
+                };
+
+                if remove {
+                    borrowed.remove(&self.var_ptr);
+                }
+            });
+        }
+    }
+
+    fn variables_borrowed() -> bool
+    {
+        BORROWED_VARIABLES.with(|borrowed| !borrowed.borrow().is_empty())
+    }
+
+    // Script run meanwhile could REDIM or reassign what VariableRef and ArrayRef point to,
+    // so calling back into script waits until the keyword drops them
+    fn check_script_reentry(engine: Engine) -> Result<(), RunTimeError>
+    {
+        if variables_borrowed() {
+            raise_runtime_error(engine, RunTimeError::ModuleSpecific, "Script cannot be called while keyword holds its variables");
+            return Err(RunTimeError::ModuleSpecific);
+        }
+
+        Ok(())
+    }
+
+    // Script variable passed to a keyword, meant to be used until the keyword returns.
+    // Engine is Copy, so the lifetime does not enforce that, it only keeps the reference from being stored as 'static.
+    pub struct VariableRef<'e, T: VariableValue>
+    {
+        engine: Engine,
+        var_ptr: usize,
+        abs_pos: i32,
+        var_type: VariableType,
+        data_ptr: *mut u8,
+        _borrow: VariableBorrow,
+        _value: ::std::marker::PhantomData<(T, &'e Engine)>
+    }
+
+    impl<'e, T: VariableValue> VariableRef<'e, T>
+    {
+        pub fn var_type(&self) -> VariableType {
+            self.var_type
+        }
+
+        pub fn get(&self) -> T {
+            unsafe {
+                T::read(self.var_type, self.data_ptr)
+            }
+        }
+
+        pub fn set(&self, value: T) -> Result<(), RunTimeError> {
+            unsafe {
+                T::write(self.engine, self.var_ptr, self.abs_pos, self.var_type, self.data_ptr, value)
+            }
+        }
+    }
+
+    // Resolves engine variable pointer and absolute position to the variable type and data of the element
+    unsafe fn variable_element_info(engine: Engine, lib: &libloading::Library, var_ptr: usize, abs_pos: i32) -> Result<(VariableType, bool, *mut u8), RunTimeError>
+    {
+        let thinbasic_variablegetinfoptr: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize, main_type: *mut i32, sub_type: *mut i32, is_array: *mut i32) -> i32> = optional_export(engine, lib, "thinBasic_VariableGetInfoPtr")?;
+        let thinbasic_directptrtodataptr: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize) -> usize> = optional_export(engine, lib, "thinBasic_DirectPtrToDataPtr")?;
+
+        let mut main_type: i32 = 0;
+        let mut sub_type: i32 = 0;
+        let mut is_array: i32 = 0;
+        thinbasic_variablegetinfoptr(var_ptr, &mut main_type, &mut sub_type, &mut is_array);
+
+        let var_type = VariableType::from_main_type(main_type);
+        let data_ptr = thinbasic_directptrtodataptr(var_ptr) as *mut u8;
+
+        Ok((var_type, is_array != 0, if data_ptr.is_null() { data_ptr } else { data_ptr.add(element_offset(abs_pos, var_type.element_size())) }))
+    }
+
+    // Absolute positions of array elements are 1 based, scalar variable comes as 0
+    const SCALAR_ABS_POS: i32 = 0;
+
+    fn element_offset(abs_pos: i32, element_size: usize) -> usize
+    {
+        (abs_pos.max(1) - 1) as usize * element_size
+    }
+
+    // Parses variable passed as keyword argument, returns engine variable pointer and absolute position of the element
+    unsafe fn parse_variable(engine: Engine) -> Result<(usize, i32), RunTimeError>
+    {
+        let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+        let thinbasic_variableparse: libloading::Symbol<unsafe extern "system" fn(var_ptr: *mut usize, abs_pos: *mut i32) -> i32> = optional_export(engine, &lib, "thinBasic_VariableParse")?;
+
+        let mut var_ptr: usize = 0;
+        let mut abs_pos: i32 = 0;
+
+        if thinbasic_variableparse(&mut var_ptr, &mut abs_pos) == 0 {
+            raise_runtime_error(engine, RunTimeError::VariableExpected, "Variable expected as parameter");
+            return Err(RunTimeError::VariableExpected);
+        }
+
+        if var_ptr == 0 {
+            raise_runtime_error(engine, RunTimeError::NotVar, "Parameter is not a variable");
+            return Err(RunTimeError::NotVar);
+        }
+
+        Ok((var_ptr, abs_pos))
+    }
+
+    // Parses variable passed as keyword argument, so the keyword can write the result back to it
+    #[allow(dead_code)]
+    pub fn parse_variable_ref<T: VariableValue>(engine: &Engine) -> Result<VariableRef<'_, T>, RunTimeError>
+    {
+        let engine = *engine;
+
+        unsafe
+        {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let (var_ptr, abs_pos) = parse_variable(engine)?;
+
+            let (var_type, _, data_ptr) = variable_element_info(engine, &lib, var_ptr, abs_pos)?;
+
+            if !T::accepts(var_type) || data_ptr.is_null() {
+                raise_runtime_error(engine, T::mismatch_error(), "Variable type does not match the parameter");
+                return Err(T::mismatch_error());
+            }
+
+            let borrow = match VariableBorrow::new(var_ptr, false) {
+                Some(borrow) => borrow,
+                None => {
+                    raise_runtime_error(engine, RunTimeError::ModuleSpecific, "Variable is in use as array");
+                    return Err(RunTimeError::ModuleSpecific);
+                }
+            };
+
+            Ok(VariableRef { engine, var_ptr, abs_pos, var_type, data_ptr, _borrow: borrow, _value: ::std::marker::PhantomData })
+        }
+    }
+
+    // Shape of a script variable looked up by name
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct VariableInfo
+    {
+        pub var_type: VariableType,
+        pub is_array: bool,
+        pub dimensions: usize,
+        pub elements: usize
+    }
+
+    // Array properties as understood by thinBasic_ArrayGetInfo, bounds are queried per dimension starting at 1
+    const ARRAY_INFO_ELEMENTS: i32 = 1;
+    const ARRAY_INFO_DIMENSIONS: i32 = 2;
+    const ARRAY_INFO_LBOUND: i32 = 10;
+    const ARRAY_INFO_UBOUND: i32 = 20;
+
+    // thinBasic arrays can have up to 3 dimensions
+    const ARRAY_REDIM_MAX_DIMENSIONS: usize = 3;
+
+    // Looks up variable visible from the current script scope, returns engine pointer, info and data pointer
+    unsafe fn find_variable(engine: Engine, lib: &libloading::Library, variable_name: &str) -> Result<(usize, VariableInfo, *mut u8), RunTimeError>
+    {
+        let thinbasic_variableexists: libloading::Symbol<unsafe extern "system" fn(variable_name: TBStr) -> i32> = optional_export(engine, lib, "thinBasic_VariableExists")?;
+
+        if thinbasic_variableexists(TBStr::from(variable_name)) == 0 {
+            return Err(RunTimeError::VariableNotDefined);
+        }
+
+        let thinbasic_variablegetinfoex: libloading::Symbol<unsafe extern "system" fn(variable_name: TBStr, main_type: *mut i32, sub_type: *mut i32, is_array: *mut i32, data_ptr: *mut usize, elements: *mut i32, level: i32) -> usize> = optional_export(engine, lib, "thinBasic_VariableGetInfoEX")?;
+
+        let mut main_type: i32 = 0;
+        let mut sub_type: i32 = 0;
+        let mut is_array: i32 = 0;
+        let mut data_ptr: usize = 0;
+        let mut elements: i32 = 0;
 
-            num
+        let var_ptr = thinbasic_variablegetinfoex(TBStr::from(variable_name), &mut main_type, &mut sub_type, &mut is_array, &mut data_ptr, &mut elements, 0);
+        if var_ptr == 0 {
+            return Err(RunTimeError::VariableNotDefined);
         }
+
+        let dimensions = if is_array != 0 {
+            let thinbasic_arraygetinfo: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize, info: i32) -> i32> = optional_export(engine, lib, "thinBasic_ArrayGetInfo")?;
+            thinbasic_arraygetinfo(var_ptr, ARRAY_INFO_DIMENSIONS).max(1) as usize
+        } else {
+            0
+        };
+
+        let info = VariableInfo {
+            var_type: VariableType::from_main_type(main_type),
+            is_array: is_array != 0,
+            dimensions,
+            elements: elements.max(1) as usize
+        };
+
+        Ok((var_ptr, info, data_ptr as *mut u8))
     }
 
+    // False also on engine without thinBasic_VariableExists, after raising the error
     #[allow(dead_code)]
-    pub fn parse_f32() -> f32
+    pub fn variable_exists(engine: Engine, variable_name: &str) -> bool
     {
         unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_parsesingle: libloading::Symbol<unsafe extern fn(*const f32)> = lib.get(b"thinBasic_ParseSingle").unwrap();
-            let num: f32 = 0.0;
-            thinbasic_parsesingle(&num);
+            let thinbasic_variableexists: libloading::Symbol<unsafe extern "system" fn(variable_name: TBStr) -> i32> = match optional_export(engine, &lib, "thinBasic_VariableExists") {
+                Ok(thinbasic_variableexists) => thinbasic_variableexists,
+                Err(_) => return false
+            };
 
-            num
+            thinbasic_variableexists(TBStr::from(variable_name)) != 0
         }
     }
 
     #[allow(dead_code)]
-    pub fn parse_f64() -> f64
+    pub fn variable_info(engine: Engine, variable_name: &str) -> Result<VariableInfo, RunTimeError>
     {
         unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_parsedouble: libloading::Symbol<unsafe extern fn(*const f64)> = lib.get(b"thinBasic_ParseDouble").unwrap();
-            let num: f64 = 0.0;
-            thinbasic_parsedouble(&num);
 
-            num
+            find_variable(engine, &lib, variable_name).map(|(_, info, _)| info)
         }
     }
 
+    // Handle to scalar variable by name, for repeated reads and writes
     #[allow(dead_code)]
-    pub fn parse_tbstr() -> TBStr
+    pub fn variable_ref<'e, T: VariableValue>(engine: &'e Engine, variable_name: &str) -> Result<VariableRef<'e, T>, RunTimeError>
     {
+        let engine = *engine;
+
         unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_parsestr: libloading::Symbol<unsafe extern fn(*const TBStr)> = lib.get(b"thinBasic_ParseStr").unwrap();
-            let text: TBStr = TBStr::from(" ");
-            thinbasic_parsestr(&text);
+            let (var_ptr, info, data_ptr) = find_variable(engine, &lib, variable_name)?;
 
-            text
+            if info.is_array || !T::accepts(info.var_type) || data_ptr.is_null() {
+                return Err(RunTimeError::InvalidDataType);
+            }
+
+            let borrow = VariableBorrow::new(var_ptr, false).ok_or(RunTimeError::ModuleSpecific)?;
+
+            // Same position thinBasic_VariableParse reports for scalar, passed on to thinBasic_ChangeVariableStringDirect
+            Ok(VariableRef { engine, var_ptr, abs_pos: SCALAR_ABS_POS, var_type: info.var_type, data_ptr, _borrow: borrow, _value: ::std::marker::PhantomData })
         }
     }
 
     #[allow(dead_code)]
-    pub fn check_comma() -> bool
+    pub fn get_variable<T: VariableValue>(engine: Engine, variable_name: &str) -> Result<T, RunTimeError>
     {
-        unsafe
-        {
-            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_checkcomma_mandatory: libloading::Symbol<unsafe extern fn() -> i32> = lib.get(b"thinBasic_CheckComma_Mandatory").unwrap();
-            let result = thinbasic_checkcomma_mandatory();
+        variable_ref::<T>(&engine, variable_name).map(|variable| variable.get())
+    }
 
-            return if result == 0 { false } else { true }
+    #[allow(dead_code)]
+    pub fn set_variable<T: VariableValue>(engine: Engine, variable_name: &str, value: T) -> Result<(), RunTimeError>
+    {
+        variable_ref::<T>(&engine, variable_name).and_then(|variable| variable.set(value))
+    }
+
+    /*
+
+     Arrays
+
+    */
+
+    // Rust types with the same memory layout as elements of numeric script arrays
+    pub trait ArrayElement: Copy
+    {
+        const VAR_TYPE: VariableType;
+    }
+
+    impl ArrayElement for u8  { const VAR_TYPE: VariableType = VariableType::U8; }
+    impl ArrayElement for i16 { const VAR_TYPE: VariableType = VariableType::I16; }
+    impl ArrayElement for u16 { const VAR_TYPE: VariableType = VariableType::U16; }
+    impl ArrayElement for u32 { const VAR_TYPE: VariableType = VariableType::U32; }
+    impl ArrayElement for i32 { const VAR_TYPE: VariableType = VariableType::I32; }
+    impl ArrayElement for i64 { const VAR_TYPE: VariableType = VariableType::I64; }
+    impl ArrayElement for f32 { const VAR_TYPE: VariableType = VariableType::F32; }
+    impl ArrayElement for f64 { const VAR_TYPE: VariableType = VariableType::F64; }
+
+    // Whole script array, meant to be used until the keyword returns, as VariableRef.
+    // Only one ArrayRef of an array exists at a time and no VariableRef of its elements meanwhile,
+    // parse_array and array_ref fail otherwise, so slices never see writes made elsewhere.
+    pub struct ArrayRef<'e>
+    {
+        engine: Engine,
+        var_ptr: usize,
+        var_type: VariableType,
+        bounds: Vec<(i32, i32)>,
+        elements: usize,
+        data_ptr: *mut u8,
+        _borrow: VariableBorrow,
+        _engine: ::std::marker::PhantomData<&'e Engine>
+    }
+
+    impl<'e> ArrayRef<'e>
+    {
+        pub fn element_type(&self) -> VariableType {
+            self.var_type
+        }
+
+        pub fn dimensions(&self) -> usize {
+            self.bounds.len()
+        }
+
+        // LBound and UBound of dimension, dimensions are numbered from 1 like in script
+        pub fn bounds(&self, dimension: usize) -> Option<(i32, i32)> {
+            if dimension == 0 { None } else { self.bounds.get(dimension - 1).cloned() }
+        }
+
+        pub fn len(&self) -> usize {
+            self.elements
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.elements == 0
+        }
+
+        // Position of element in flat data, first index changes fastest as in thinBasic memory
+        pub fn offset(&self, indexes: &[i32]) -> Result<usize, RunTimeError> {
+            if indexes.len() != self.bounds.len() {
+                return Err(RunTimeError::ArrayOutOfBound);
+            }
+
+            let mut offset: usize = 0;
+            let mut stride: usize = 1;
+            for (&index, &(lower, upper)) in indexes.iter().zip(self.bounds.iter()) {
+                if index < lower || index > upper {
+                    return Err(RunTimeError::ArrayOutOfBound);
+                }
+                offset += (index - lower) as usize * stride;
+                stride *= (upper - lower + 1) as usize;
+            }
+
+            Ok(offset)
+        }
+
+        pub fn as_slice<T: ArrayElement>(&self) -> Result<&[T], RunTimeError> {
+            if T::VAR_TYPE != self.var_type {
+                return Err(RunTimeError::InvalidDataType);
+            }
+
+            // Undimensioned array has no data
+            if self.elements == 0 || self.data_ptr.is_null() {
+                return Ok(&[]);
+            }
+
+            unsafe {
+                Ok(::std::slice::from_raw_parts(self.data_ptr as *const T, self.elements))
+            }
+        }
+
+        pub fn as_mut_slice<T: ArrayElement>(&mut self) -> Result<&mut [T], RunTimeError> {
+            if T::VAR_TYPE != self.var_type {
+                return Err(RunTimeError::InvalidDataType);
+            }
+
+            if self.elements == 0 || self.data_ptr.is_null() {
+                return Ok(&mut []);
+            }
+
+            unsafe {
+                Ok(::std::slice::from_raw_parts_mut(self.data_ptr as *mut T, self.elements))
+            }
+        }
+
+        // Copy of the whole array, converting numeric element types when needed
+        pub fn to_vec<T: VariableValue>(&self) -> Result<Vec<T>, RunTimeError> {
+            if !T::accepts(self.var_type) {
+                return Err(RunTimeError::InvalidDataType);
+            }
+
+            Ok((0..self.elements).map(|i| unsafe { T::read(self.var_type, self.element_ptr(i)) }).collect())
+        }
+
+        pub fn get<T: VariableValue>(&self, indexes: &[i32]) -> Result<T, RunTimeError> {
+            if !T::accepts(self.var_type) {
+                return Err(RunTimeError::InvalidDataType);
+            }
+            let offset = self.offset(indexes)?;
+
+            unsafe {
+                Ok(T::read(self.var_type, self.element_ptr(offset)))
+            }
+        }
+
+        // Takes &mut self, slices from as_slice must not see the write
+        pub fn set<T: VariableValue>(&mut self, indexes: &[i32], value: T) -> Result<(), RunTimeError> {
+            if !T::accepts(self.var_type) {
+                return Err(RunTimeError::InvalidDataType);
+            }
+            let offset = self.offset(indexes)?;
+
+            unsafe {
+                T::write(self.engine, self.var_ptr, offset as i32 + 1, self.var_type, self.element_ptr(offset), value)
+            }
+        }
+
+        pub fn strings(&self) -> Result<ArrayStrings<'_, 'e>, RunTimeError> {
+            if self.var_type != VariableType::TBSTR {
+                return Err(RunTimeError::VariableMustbeStringType);
+            }
+
+            Ok(ArrayStrings { array: self, position: 0 })
+        }
+
+        // Changes array to new dimensions, each given as number of elements with LBound 1
+        // Engine refuses PRESERVE for arrays declared AT absolute address and reports it as the last error
+        pub fn redim(&mut self, dimensions: &[usize], preserve: bool) -> Result<(), RunTimeError> {
+            // Engine takes element counts as LONG
+            if dimensions.is_empty() || dimensions.len() > ARRAY_REDIM_MAX_DIMENSIONS || dimensions.iter().any(|&dimension| dimension > i32::MAX as usize) {
+                raise_runtime_error(self.engine, RunTimeError::ArrayOutOfBound, "Array dimensions not supported by REDIM");
+                return Err(RunTimeError::ArrayOutOfBound);
+            }
+
+            unsafe {
+                let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+                let thinbasic_arrayredim: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize, preserve: i32, dimension_count: i32, d1: i32, d2: i32, d3: i32) -> i32> = optional_export(self.engine, &lib, "thinBasic_ArrayRedim")?;
+
+                let mut counts = [0i32; ARRAY_REDIM_MAX_DIMENSIONS];
+                for (count, &dimension) in counts.iter_mut().zip(dimensions.iter()) {
+                    *count = dimension as i32;
+                }
+
+                thinbasic_arrayredim(self.var_ptr, preserve as i32, dimensions.len() as i32, counts[0], counts[1], counts[2]);
+                if !error_free(self.engine) {
+                    return Err(get_last_error(self.engine));
+                }
+
+                let (bounds, elements, data_ptr) = array_layout(self.engine, &lib, self.var_ptr)?;
+                self.bounds = bounds;
+                self.elements = elements;
+                self.data_ptr = data_ptr;
+            }
+
+            Ok(())
+        }
+
+        // Redimensions array to one dimension and stores all values into it
+        pub fn fill<T: VariableValue, I: IntoIterator<Item = T>>(&mut self, values: I) -> Result<(), RunTimeError> {
+            if !T::accepts(self.var_type) {
+                raise_runtime_error(self.engine, RunTimeError::RedimNewTypeNotSupported, "Values do not match the array type");
+                return Err(RunTimeError::RedimNewTypeNotSupported);
+            }
+
+            let values: Vec<T> = values.into_iter().collect();
+            self.redim(&[values.len()], false)?;
+
+            for (offset, value) in values.into_iter().enumerate() {
+                unsafe {
+                    T::write(self.engine, self.var_ptr, offset as i32 + 1, self.var_type, self.element_ptr(offset), value)?;
+                }
+            }
+
+            Ok(())
+        }
+
+        // Same as fill, but keeps elements beyond values.len() when the array is already bigger
+        // Multidimensional array keeps its dimensions, only the last one grows to fit the values
+        pub fn fill_preserve<T: VariableValue + Clone>(&mut self, values: &[T]) -> Result<(), RunTimeError> {
+            if !T::accepts(self.var_type) {
+                raise_runtime_error(self.engine, RunTimeError::RedimNewTypeNotSupported, "Values do not match the array type");
+                return Err(RunTimeError::RedimNewTypeNotSupported);
+            }
+
+            if values.len() > self.elements {
+                self.redim(&preserved_dimensions(&self.bounds, values.len()), true)?;
+            }
+
+            for (offset, value) in values.iter().enumerate() {
+                unsafe {
+                    T::write(self.engine, self.var_ptr, offset as i32 + 1, self.var_type, self.element_ptr(offset), value.clone())?;
+                }
+            }
+
+            Ok(())
+        }
+
+        fn element_ptr(&self, offset: usize) -> *mut u8 {
+            unsafe {
+                self.data_ptr.add(offset * self.var_type.element_size())
+            }
         }
     }
 
-    #[allow(dead_code)]
-    pub fn check_comma_optional() -> bool
+    // Element counts for PRESERVE with at least the given number of elements
+    // Growing only the last dimension keeps existing elements at their flat offsets, first index changes fastest
+    fn preserved_dimensions(bounds: &[(i32, i32)], elements: usize) -> Vec<usize>
     {
-        unsafe
-        {
-            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_checkcomma_optional: libloading::Symbol<unsafe extern fn() -> i32> = lib.get(b"thinBasic_CheckComma_Optional").unwrap();
-            let result = thinbasic_checkcomma_optional();
+        let mut dimensions: Vec<usize> = bounds.iter().map(|&(lower, upper)| (upper - lower + 1).max(0) as usize).collect();
+        let inner: usize = dimensions.iter().rev().skip(1).product();
 
-            return if result == 0 { false } else { true }
+        match dimensions.last_mut() {
+            Some(last) if inner > 0 => *last = (*last).max(elements.div_ceil(inner)),
+            _ => return vec![elements]
         }
+
+        dimensions
     }
 
-    #[allow(dead_code)]
-    pub fn check_open_parens() -> bool
+    pub struct ArrayStrings<'a, 'e>
     {
-        unsafe
-        {
-            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_checkopenparens_mandatory: libloading::Symbol<unsafe extern fn() -> i32> = lib.get(b"thinBasic_CheckOpenParens_Mandatory").unwrap();
-            let result = thinbasic_checkopenparens_mandatory();
+        array: &'a ArrayRef<'e>,
+        position: usize
+    }
 
-            return if result == 0 { false } else { true }
+    impl<'a, 'e> Iterator for ArrayStrings<'a, 'e>
+    {
+        type Item = String;
+
+        fn next(&mut self) -> Option<String> {
+            if self.position >= self.array.elements {
+                return None;
+            }
+
+            let text = unsafe { String::read(VariableType::TBSTR, self.array.element_ptr(self.position)) };
+            self.position += 1;
+
+            Some(text)
         }
     }
 
-    #[allow(dead_code)]
-    pub fn check_open_parens_optional() -> bool
+    // Bounds, element count and data
+    type ArrayLayout = (Vec<(i32, i32)>, usize, *mut u8);
+
+    // Layout of array as the engine has it now
+    unsafe fn array_layout(engine: Engine, lib: &libloading::Library, var_ptr: usize) -> Result<ArrayLayout, RunTimeError>
     {
-        unsafe
-        {
-            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_checkopenparens_optional: libloading::Symbol<unsafe extern fn() -> i32> = lib.get(b"thinBasic_CheckOpenParens_Optional").unwrap();
-            let result = thinbasic_checkopenparens_optional();
+        let thinbasic_arraygetinfo: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize, info: i32) -> i32> = optional_export(engine, lib, "thinBasic_ArrayGetInfo")?;
+        let thinbasic_directptrtodataptr: libloading::Symbol<unsafe extern "system" fn(var_ptr: usize) -> usize> = optional_export(engine, lib, "thinBasic_DirectPtrToDataPtr")?;
 
-            return if result == 0 { false } else { true }
+        let dimensions = thinbasic_arraygetinfo(var_ptr, ARRAY_INFO_DIMENSIONS).max(1);
+        let bounds = (1..=dimensions)
+            .map(|dimension| (thinbasic_arraygetinfo(var_ptr, ARRAY_INFO_LBOUND + dimension), thinbasic_arraygetinfo(var_ptr, ARRAY_INFO_UBOUND + dimension)))
+            .collect();
+        let elements = thinbasic_arraygetinfo(var_ptr, ARRAY_INFO_ELEMENTS).max(0) as usize;
+
+        Ok((bounds, elements, thinbasic_directptrtodataptr(var_ptr) as *mut u8))
+    }
+
+    unsafe fn array_from_variable<'e>(engine: Engine, lib: &libloading::Library, var_ptr: usize, var_type: VariableType) -> Result<ArrayRef<'e>, RunTimeError>
+    {
+        if var_type.element_size() == 0 {
+            raise_runtime_error(engine, RunTimeError::InvalidDataType, "Array element type is not supported");
+            return Err(RunTimeError::InvalidDataType);
         }
+
+        let borrow = match VariableBorrow::new(var_ptr, true) {
+            Some(borrow) => borrow,
+            None => {
+                raise_runtime_error(engine, RunTimeError::ModuleSpecific, "Array is already in use");
+                return Err(RunTimeError::ModuleSpecific);
+            }
+        };
+        let (bounds, elements, data_ptr) = array_layout(engine, lib, var_ptr)?;
+
+        Ok(ArrayRef { engine, var_ptr, var_type, bounds, elements, data_ptr, _borrow: borrow, _engine: ::std::marker::PhantomData })
     }
 
+    // Parses whole array passed as keyword argument
     #[allow(dead_code)]
-    pub fn check_close_parens() -> bool
+    pub fn parse_array(engine: &Engine) -> Result<ArrayRef<'_>, RunTimeError>
     {
+        let engine = *engine;
+
         unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_checkcloseparens_mandatory: libloading::Symbol<unsafe extern fn() -> i32> = lib.get(b"thinBasic_CheckCloseParens_Mandatory").unwrap();
-            let result = thinbasic_checkcloseparens_mandatory();
+            let (var_ptr, _) = parse_variable(engine)?;
 
-            return if result == 0 { false } else { true }
+            let (var_type, is_array, _) = variable_element_info(engine, &lib, var_ptr, 1)?;
+            if !is_array {
+                raise_runtime_error(engine, RunTimeError::VariableIsNotArray, "Variable passed as parameter is not an array");
+                return Err(RunTimeError::VariableIsNotArray);
+            }
+
+            array_from_variable(engine, &lib, var_ptr, var_type)
         }
     }
 
     #[allow(dead_code)]
-    pub fn check_close_parens_optional() -> bool
+    pub fn array_ref<'e>(engine: &'e Engine, variable_name: &str) -> Result<ArrayRef<'e>, RunTimeError>
     {
+        let engine = *engine;
+
         unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_checkcloseparens_optional: libloading::Symbol<unsafe extern fn() -> i32> = lib.get(b"thinBasic_CheckCloseParens_Optional").unwrap();
-            let result = thinbasic_checkcloseparens_optional();
+            let (var_ptr, info, _) = find_variable(engine, &lib, variable_name)?;
 
-            return if result == 0 { false } else { true }
-        }
-    }   
+            if !info.is_array {
+                return Err(RunTimeError::VariableIsNotArray);
+            }
 
+            array_from_variable(engine, &lib, var_ptr, info.var_type)
+        }
+    }
 
     /*
 
@@ -343,7 +2454,7 @@ pub mod core
     */
 
     #[allow(dead_code)]
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum RunTimeError
     {
         NoError                                 = 0,
@@ -552,26 +2663,190 @@ pub mod core
         ComGeneric                              = 30000
     }
 
+    impl RunTimeError
+    {
+        // Engine may report codes this binding has no variant for
+        pub fn from_code(code: i32) -> Option<RunTimeError> {
+            Some(match code {
+                0     => RunTimeError::NoError,
+                1     => RunTimeError::Parens,
+                2     => RunTimeError::NoExp,
+                3     => RunTimeError::DivZero,
+                4     => RunTimeError::EqualExp,
+                5     => RunTimeError::NotVar,
+                6     => RunTimeError::LabTabFull,
+                7     => RunTimeError::DupLab,
+                8     => RunTimeError::UndefTab,
+                9     => RunTimeError::ThenExpected,
+                10    => RunTimeError::ToExpected,
+                11    => RunTimeError::TooManyFor,
+                12    => RunTimeError::NextWithoutFor,
+                13    => RunTimeError::MissingSemicolon,
+                14    => RunTimeError::CommandRetNoneInExpression,
+                15    => RunTimeError::MissQuote,
+                16    => RunTimeError::BadFile,
+                17    => RunTimeError::StrExpected,
+                18    => RunTimeError::UnknownKeyword,
+                19    => RunTimeError::MissingOpenparens,
+                20    => RunTimeError::MissingCloseparens,
+                21    => RunTimeError::MissingComma,
+                22    => RunTimeError::MissingSquarecloseparens,
+                23    => RunTimeError::EolIncorrect,
+                24    => RunTimeError::DoubleConcatenation,
+                25    => RunTimeError::CommandUnknownReturnedParameter,
+                26    => RunTimeError::PrimitiveStr,
+                27    => RunTimeError::PrimitiveNum,
+                28    => RunTimeError::PrintError,
+                29    => RunTimeError::PrintErrorNoendofline,
+                30    => RunTimeError::VariableNotDefined,
+                31    => RunTimeError::AtomTokenTypeNumeric,
+                32    => RunTimeError::IfWithoutEndif,
+                33    => RunTimeError::IfEndifWithoutIf,
+                34    => RunTimeError::TooManyWhile,
+                35    => RunTimeError::WhileWithoutWend,
+                36    => RunTimeError::DimTypeNotDefined,
+                37    => RunTimeError::ExitNoKeyFound,
+                38    => RunTimeError::NoEndFunctionFound,
+                39    => RunTimeError::FunctionNameDuplicate,
+                40    => RunTimeError::FunctionNameIsKey,
+                41    => RunTimeError::FunctionNameIsVar,
+                42    => RunTimeError::FunctionNameIsUdt,
+                43    => RunTimeError::EndNoKeyFound,
+                44    => RunTimeError::DimMissingAs,
+                45    => RunTimeError::UndefinedToken,
+                46    => RunTimeError::UnsupportedChar,
+                47    => RunTimeError::FunctionDeclareMissingAs,
+                48    => RunTimeError::DeclareFunctionOrSubExpected,
+                49    => RunTimeError::DoLoopWrongCondition,
+                50    => RunTimeError::MissingAlias,
+                51    => RunTimeError::MissingLib,
+                52    => RunTimeError::UndefinedVarType,
+                53    => RunTimeError::ParameterByrefIncorrectPtr,
+                55    => RunTimeError::LoopExpectedWhileOrUntil,
+                58    => RunTimeError::EndFunctionWithoutFunction,
+                59    => RunTimeError::EndFunctionFound,
+                60    => RunTimeError::IterateNoKeyFound,
+                64    => RunTimeError::WithEndWithError,
+                65    => RunTimeError::TooNestedDoloop,
+                66    => RunTimeError::DoLoopMissingDoOrLoop,
+                70    => RunTimeError::VariableNotDimensioned,
+                71    => RunTimeError::VariableIsNotArray,
+                72    => RunTimeError::VariableMustbeStringType,
+                73    => RunTimeError::RedimNewTypeNotSupported,
+                74    => RunTimeError::RedimPreserveNotValidAbsolute,
+                75    => RunTimeError::VariableMustBeUdtType,
+                76    => RunTimeError::KeywordNotExpected,
+                77    => RunTimeError::TokenNotExpected,
+                80    => RunTimeError::IncludeFileNotFound,
+                85    => RunTimeError::DimUnexpectedKeyword,
+                90    => RunTimeError::FunctionNotSupported,
+                91    => RunTimeError::ArrayFunctionNotSupported,
+                100   => RunTimeError::UdtElementNotFound,
+                101   => RunTimeError::UdtExpected,
+                102   => RunTimeError::UdtEquOrElementExpected,
+                110   => RunTimeError::AssignmentNotSupported,
+                115   => RunTimeError::RelationalExpected,
+                120   => RunTimeError::ApiLibNotFound,
+                121   => RunTimeError::ApiFunctionNotFoundInLib,
+                122   => RunTimeError::ApiGeneralAddressNotPresent,
+                130   => RunTimeError::CallNotSupportedStatement,
+                131   => RunTimeError::CallFunctionNotFound,
+                133   => RunTimeError::FunctionNotFound,
+                134   => RunTimeError::FunctionExpectedCallback,
+                135   => RunTimeError::EquateAlreadyDefined,
+                136   => RunTimeError::EquateAlreadyDefinedDifferent,
+                142   => RunTimeError::VariableNameDuplicateGlobal,
+                144   => RunTimeError::VariableNameDuplicateFunction,
+                145   => RunTimeError::VariableNameDuplicateLocal,
+                146   => RunTimeError::VariableNameDuplicate,
+                150   => RunTimeError::ForStepShouldBeNegative,
+                151   => RunTimeError::ForStepShouldBePositive,
+                152   => RunTimeError::ForExpectedAVariable,
+                153   => RunTimeError::ForStepIsZero,
+                154   => RunTimeError::ForVarMustBeNumeric,
+                160   => RunTimeError::AliasCommandNameExpected,
+                161   => RunTimeError::AliasAsExpected,
+                162   => RunTimeError::AliasUndefNotUndef,
+                169   => RunTimeError::TypeMissingEndUnion,
+                170   => RunTimeError::TypeMissingEndType,
+                171   => RunTimeError::TypeMissingEndClass,
+                172   => RunTimeError::TypeTypeNotDefined,
+                173   => RunTimeError::TypeMissingAs,
+                174   => RunTimeError::TypeNameMustbeUndefined,
+                175   => RunTimeError::TypeArrayMustbeDimensioned,
+                176   => RunTimeError::TypeStringsMustHaveSize,
+                177   => RunTimeError::TypeElementAlreadyPresent,
+                178   => RunTimeError::TypeElementAlreadyPresentInherit,
+                179   => RunTimeError::TypeDynstringInsideUnion,
+                180   => RunTimeError::NoEndRawtextFound,
+                190   => RunTimeError::BeginBlockUnsuported,
+                191   => RunTimeError::BeginConstMissingEnd,
+                220   => RunTimeError::FunctionParamUnrecognizedType,
+                245   => RunTimeError::DoWithoutLoop,
+                246   => RunTimeError::FunctionMissingAs,
+                247   => RunTimeError::RegexprMissingTo,
+                248   => RunTimeError::RegexprMissingIn,
+                249   => RunTimeError::SelectWithoutEndSelect,
+                250   => RunTimeError::DuplicateSymbol,
+                251   => RunTimeError::InvalidnumericChar,
+                252   => RunTimeError::InvalidDelimiter,
+                253   => RunTimeError::InvalidDataType,
+                254   => RunTimeError::VariableExpected,
+                255   => RunTimeError::VariableVariantExpected,
+                270   => RunTimeError::SelectMissingCase,
+                271   => RunTimeError::SelectErrorKindofOperation,
+                272   => RunTimeError::SelectCodeBetweenSelectCase,
+                280   => RunTimeError::StrptrVariableNotADynstringNum,
+                281   => RunTimeError::StrptrVariableNotADynstringVar,
+                282   => RunTimeError::StrptrVariableNotADynstringUdt,
+                300   => RunTimeError::ApicallRefExpected,
+                400   => RunTimeError::ArrayOutOfBound,
+                500   => RunTimeError::ModuleSpecific,
+                800   => RunTimeError::PreparserDirectiveNotSupported,
+                820   => RunTimeError::PreparserScriptVersionRequest,
+                900   => RunTimeError::InternalReturnMainType,
+                910   => RunTimeError::InternalDecription,
+                915   => RunTimeError::InternalUdtBufferShort,
+                921   => RunTimeError::InternalReturnNoneNoCodePtr,
+                922   => RunTimeError::InternalReturnNumberNoCodePtr,
+                923   => RunTimeError::InternalReturnStringNoCodePtr,
+                5010  => RunTimeError::ClassNewNoIndexAllowed,
+                5015  => RunTimeError::ClassNewDifferentClass,
+                5020  => RunTimeError::ClassNewNoClass,
+                5025  => RunTimeError::ClassNewExpectedNew,
+                5030  => RunTimeError::ClassNotInitWithNew,
+                5035  => RunTimeError::ClassSetNowAllowed,
+                5100  => RunTimeError::ClassMethodPropertyNotfound,
+                5110  => RunTimeError::ClassExpected,
+                11000 => RunTimeError::TraceStopByUser,
+                12000 => RunTimeError::ObfuscationFileNotValid,
+                30000 => RunTimeError::ComGeneric,
+                _     => return None
+            })
+        }
+    }
+
     #[allow(dead_code)]
-    pub fn get_last_error() -> RunTimeError
+    pub fn get_last_error(_engine: Engine) -> RunTimeError
     {
         unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_getlasterror: libloading::Symbol<unsafe extern fn() -> RunTimeError> = lib.get(b"thinBasic_GetLastError").unwrap();
+            let thinbasic_getlasterror: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_GetLastError").unwrap();
             let result = thinbasic_getlasterror();
 
-            return result;
+            // Codes without variant are still errors, reported by the engine already
+            return RunTimeError::from_code(result).unwrap_or(RunTimeError::ModuleSpecific);
         }
     }
 
     #[allow(dead_code)]
-    pub fn error_free() -> bool
+    pub fn error_free(_engine: Engine) -> bool
     {
         unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_errorfree: libloading::Symbol<unsafe extern fn() -> i32> = lib.get(b"thinBasic_ErrorFree").unwrap();
+            let thinbasic_errorfree: libloading::Symbol<unsafe extern "system" fn() -> i32> = lib.get(b"thinBasic_ErrorFree").unwrap();
             let result = thinbasic_errorfree();
 
             return if result == 0 { false } else { true }
@@ -579,16 +2854,310 @@ pub mod core
     }
 
     #[allow(dead_code)]
-    pub fn raise_runtime_error(error_type: RunTimeError, description: &str) -> bool
+    pub fn raise_runtime_error(_engine: Engine, error_type: RunTimeError, description: &str) -> bool
     {
         unsafe
         {
             let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
-            let thinbasic_runtimeerror: libloading::Symbol<unsafe extern fn(error_type: i32, error_description: TBStr) -> i32> = lib.get(b"thinBasic_RunTimeError").unwrap();
+            let thinbasic_runtimeerror: libloading::Symbol<unsafe extern "system" fn(error_type: i32, error_description: TBStr) -> i32> = lib.get(b"thinBasic_RunTimeError").unwrap();
 
             let result = thinbasic_runtimeerror(error_type as i32, TBStr::from(description));
 
             return if result == 0 { false } else { true }
         }
     }
+
+    /*
+
+     Interruption
+
+    */
+
+    // Stopping script in debugger, or any runtime error, leaves the engine in error state.
+    // Long loops poll it often, so the library and exports are resolved once per thread.
+    struct InterruptCheck
+    {
+        _lib: libloading::Library,
+        error_free: unsafe extern "system" fn() -> i32,
+        get_last_error: unsafe extern "system" fn() -> i32
+    }
+
+    thread_local! {
+        static INTERRUPT_CHECK: InterruptCheck = unsafe {
+            let lib: libloading::Library = libloading::Library::new("thinCore.dll").unwrap();
+            let error_free = *lib.get::<unsafe extern "system" fn() -> i32>(b"thinBasic_ErrorFree").unwrap();
+            let get_last_error = *lib.get::<unsafe extern "system" fn() -> i32>(b"thinBasic_GetLastError").unwrap();
+
+            InterruptCheck { _lib: lib, error_free, get_last_error }
+        };
+    }
+
+    // True when an error is pending, keyword should return as soon as possible.
+    // Engine exports no separate stop state, user stopping the script only shows up here as TraceStopByUser error.
+    #[allow(dead_code)]
+    pub fn error_pending(_engine: Engine) -> bool
+    {
+        INTERRUPT_CHECK.with(|check| unsafe { (check.error_free)() == 0 })
+    }
+
+    // For handlers returning Result, any pending error becomes Err with its code, TraceStopByUser when the user stopped the script
+    #[allow(dead_code)]
+    pub fn not_interrupted(engine: Engine) -> Result<(), RunTimeError>
+    {
+        if !error_pending(engine) {
+            return Ok(());
+        }
+
+        INTERRUPT_CHECK.with(|check| {
+            let last_error = unsafe { (check.get_last_error)() };
+
+            // Codes without variant become ModuleSpecific, the engine reported them already
+            Err(RunTimeError::from_code(last_error).unwrap_or(RunTimeError::ModuleSpecific))
+        })
+    }
+
+    #[cfg(test)]
+    mod tests
+    {
+        use super::{element_offset, module, preserved_dimensions, registration_names, variables_borrowed, version_requirement, ArrayRef, ExistingFunction, FunctionOptions, Engine, EngineVersion, Ext, PeekedToken, RegistrationError, RunTimeError, VariableBorrow, VariableType, MINIMUM_ENGINE_VERSION, SCALAR_ABS_POS};
+
+        fn array_with_bounds(bounds: Vec<(i32, i32)>) -> ArrayRef<'static> {
+            let elements = bounds.iter().map(|&(lower, upper)| (upper - lower + 1) as usize).product();
+
+            ArrayRef {
+                engine: unsafe { Engine::assume_script_thread() },
+                var_ptr: 1,
+                var_type: VariableType::I32,
+                bounds,
+                elements,
+                data_ptr: ::std::ptr::null_mut(),
+                _borrow: VariableBorrow::new(1, true).unwrap(),
+                _engine: ::std::marker::PhantomData
+            }
+        }
+
+        fn round_trip(value: f64) -> f64 {
+            Ext::from_f64(value).to_f64()
+        }
+
+        #[test]
+        fn ext_one_has_explicit_integer_bit() {
+            let ext = Ext::from_f64(1.0);
+            let bytes = ext.0;
+
+            assert_eq!(&bytes[0..8], &0x8000_0000_0000_0000u64.to_le_bytes());
+            assert_eq!(&bytes[8..10], &0x3FFFu16.to_le_bytes());
+        }
+
+        #[test]
+        fn ext_round_trips_normal_values() {
+            for &value in &[1.0, -1.0, 0.5, 3.25, 1e300, -1e-300, 123456789.125, f64::MAX, f64::MIN_POSITIVE, ::std::f64::consts::PI] {
+                assert_eq!(round_trip(value).to_bits(), value.to_bits(), "{}", value);
+            }
+        }
+
+        #[test]
+        fn ext_round_trips_zero_and_subnormals() {
+            assert_eq!(round_trip(0.0).to_bits(), 0.0f64.to_bits());
+            assert_eq!(round_trip(-0.0).to_bits(), (-0.0f64).to_bits());
+
+            let smallest = f64::from_bits(1);
+            assert_eq!(round_trip(smallest).to_bits(), smallest.to_bits());
+            assert_eq!(round_trip(f64::MIN_POSITIVE / 3.0).to_bits(), (f64::MIN_POSITIVE / 3.0).to_bits());
+        }
+
+        #[test]
+        fn ext_keeps_infinity_and_nan() {
+            assert_eq!(round_trip(f64::INFINITY), f64::INFINITY);
+            assert_eq!(round_trip(f64::NEG_INFINITY), f64::NEG_INFINITY);
+            assert!(round_trip(f64::NAN).is_nan());
+        }
+
+        #[test]
+        fn runtime_error_from_known_code() {
+            assert_eq!(RunTimeError::from_code(0), Some(RunTimeError::NoError));
+            assert_eq!(RunTimeError::from_code(253), Some(RunTimeError::InvalidDataType));
+            assert_eq!(RunTimeError::from_code(11000), Some(RunTimeError::TraceStopByUser));
+        }
+
+        #[test]
+        fn runtime_error_from_unknown_code() {
+            for &code in &[-1, 54, 56, 57, 999, 65535] {
+                assert_eq!(RunTimeError::from_code(code), None, "{}", code);
+            }
+        }
+
+        #[test]
+        fn engine_versions_compare_field_by_field() {
+            assert!(EngineVersion::new(1, 10, 5, 0) < EngineVersion::new(1, 10, 5, 1));
+            assert!(EngineVersion::new(1, 10, 5, 9) < EngineVersion::new(1, 10, 6, 0));
+            assert!(EngineVersion::new(1, 9, 16, 17) < EngineVersion::new(1, 10, 0, 0));
+            assert!(EngineVersion::new(1, 99, 0, 0) < EngineVersion::new(2, 0, 0, 0));
+            assert_eq!(EngineVersion::new(1, 10, 5, 0).to_string(), "1.10.5.0");
+        }
+
+        #[test]
+        fn engine_version_requirement() {
+            assert_eq!(version_requirement(Some(MINIMUM_ENGINE_VERSION), MINIMUM_ENGINE_VERSION), Ok(()));
+            assert_eq!(version_requirement(Some(EngineVersion::new(1, 11, 0, 0)), MINIMUM_ENGINE_VERSION), Ok(()));
+            assert_eq!(version_requirement(Some(EngineVersion::new(1, 9, 16, 17)), MINIMUM_ENGINE_VERSION),
+                       Err(String::from("Module requires thinBasic 1.10.5.0 or newer, running on 1.9.16.17")));
+        }
+
+        #[test]
+        fn unknown_engine_version_is_accepted() {
+            assert_eq!(version_requirement(None, MINIMUM_ENGINE_VERSION), Ok(()));
+        }
+
+        #[test]
+        fn unprefixed_aliases_never_overwrite() {
+            module::set_symbol_prefix("My_", true);
+            let options = FunctionOptions::new().alias("Plus");
+
+            assert_eq!(registration_names("Add", &options), vec![
+                (String::from("My_Add"), ExistingFunction::Overwrite),
+                (String::from("Add"), ExistingFunction::Fail),
+                (String::from("My_Plus"), ExistingFunction::Overwrite),
+                (String::from("Plus"), ExistingFunction::Fail)
+            ]);
+
+            module::set_symbol_prefix("", false);
+        }
+
+        #[test]
+        fn registration_accepts_positive_symbol_id() {
+            assert_eq!(RegistrationError::from_load_result("Test_Add", 1), Ok(1));
+            assert_eq!(RegistrationError::from_load_result("Test_Add", 420), Ok(420));
+        }
+
+        #[test]
+        fn registration_error_from_load_result() {
+            let name = String::from("Test_Add");
+
+            assert_eq!(RegistrationError::from_load_result(&name, -1), Err(RegistrationError::DuplicateSymbol(name.clone())));
+            assert_eq!(RegistrationError::from_load_result(&name, -2), Err(RegistrationError::KeywordConflict(name.clone())));
+            assert_eq!(RegistrationError::from_load_result(&name, -3), Err(RegistrationError::VariableConflict(name.clone())));
+            assert_eq!(RegistrationError::from_load_result(&name, -4), Err(RegistrationError::UdtConflict(name.clone())));
+            assert_eq!(RegistrationError::from_load_result(&name, 0), Err(RegistrationError::Failed(name.clone(), 0)));
+            assert_eq!(RegistrationError::from_load_result(&name, -5), Err(RegistrationError::Failed(name.clone(), -5)));
+        }
+
+        #[test]
+        fn peeked_string_tokens() {
+            let strings = [
+                PeekedToken::StringLiteral(String::from("text")),
+                PeekedToken::Variable(String::from("name"), VariableType::TBSTR),
+                PeekedToken::Equate(String::from("$Title")),
+                PeekedToken::Keyword(String::from("Left$"))
+            ];
+
+            for token in strings.iter() {
+                assert!(token.is_string(), "{:?}", token);
+                assert!(!token.is_numeric(), "{:?}", token);
+            }
+        }
+
+        #[test]
+        fn peeked_numeric_tokens() {
+            let numbers = [
+                PeekedToken::NumericLiteral(String::from("1.5")),
+                PeekedToken::Variable(String::from("count"), VariableType::I32),
+                PeekedToken::Variable(String::from("total"), VariableType::EXT),
+                PeekedToken::Equate(String::from("%Max"))
+            ];
+
+            for token in numbers.iter() {
+                assert!(token.is_numeric(), "{:?}", token);
+                assert!(!token.is_string(), "{:?}", token);
+            }
+        }
+
+        #[test]
+        fn peeked_tokens_of_neither_kind() {
+            let others = [
+                PeekedToken::Variable(String::from("point"), VariableType::UDT),
+                PeekedToken::Keyword(String::from("Len")),
+                PeekedToken::Punctuation(String::from("(")),
+                PeekedToken::EndOfLine,
+                PeekedToken::Other(String::from("?"))
+            ];
+
+            for token in others.iter() {
+                assert!(!token.is_string(), "{:?}", token);
+                assert!(!token.is_numeric(), "{:?}", token);
+            }
+        }
+
+        #[test]
+        fn borrowed_variables_block_script_calls_until_dropped() {
+            assert!(!variables_borrowed());
+
+            let shared = VariableBorrow::new(7, false).unwrap();
+            assert!(VariableBorrow::new(7, true).is_none());
+            assert!(variables_borrowed());
+
+            drop(shared);
+            assert!(!variables_borrowed());
+
+            let array = array_with_bounds(vec![(1, 3)]);
+            assert!(variables_borrowed());
+            drop(array);
+            assert!(!variables_borrowed());
+        }
+
+        #[test]
+        fn array_offset_first_index_changes_fastest() {
+            let array = array_with_bounds(vec![(1, 3), (0, 1)]);
+
+            assert_eq!(array.offset(&[1, 0]), Ok(0));
+            assert_eq!(array.offset(&[3, 0]), Ok(2));
+            assert_eq!(array.offset(&[1, 1]), Ok(3));
+            assert_eq!(array.offset(&[3, 1]), Ok(5));
+        }
+
+        #[test]
+        fn array_offset_respects_lower_bound() {
+            let array = array_with_bounds(vec![(-2, 2)]);
+
+            assert_eq!(array.offset(&[-2]), Ok(0));
+            assert_eq!(array.offset(&[2]), Ok(4));
+        }
+
+        #[test]
+        fn array_offset_out_of_bounds() {
+            let array = array_with_bounds(vec![(1, 3), (1, 2)]);
+
+            for indexes in &[vec![0, 1], vec![4, 1], vec![1, 0], vec![1, 3]] {
+                assert_eq!(array.offset(indexes), Err(RunTimeError::ArrayOutOfBound), "{:?}", indexes);
+            }
+        }
+
+        #[test]
+        fn array_offset_needs_index_for_every_dimension() {
+            let array = array_with_bounds(vec![(1, 3), (1, 2)]);
+
+            assert_eq!(array.offset(&[1]), Err(RunTimeError::ArrayOutOfBound));
+            assert_eq!(array.offset(&[1, 1, 1]), Err(RunTimeError::ArrayOutOfBound));
+        }
+
+        #[test]
+        fn scalar_abs_pos_matches_engine() {
+            // variable_ref and parse_variable_ref must hand the same position to thinBasic_ChangeVariableStringDirect
+            assert_eq!(SCALAR_ABS_POS, 0);
+            assert_eq!(element_offset(SCALAR_ABS_POS, 8), 0);
+            assert_eq!(element_offset(1, 8), 0);
+            assert_eq!(element_offset(3, 8), 16);
+        }
+
+        #[test]
+        fn fill_preserve_keeps_dimensions() {
+            assert_eq!(preserved_dimensions(&[(1, 3)], 5), vec![5]);
+            assert_eq!(preserved_dimensions(&[(1, 3), (1, 2)], 7), vec![3, 3]);
+            assert_eq!(preserved_dimensions(&[(1, 3), (1, 2)], 9), vec![3, 3]);
+            assert_eq!(preserved_dimensions(&[(0, 1), (1, 2), (1, 1)], 9), vec![2, 2, 3]);
+
+            // Undimensioned array gets one dimension
+            assert_eq!(preserved_dimensions(&[], 4), vec![4]);
+        }
+    }
 }
@@ -0,0 +1,1342 @@
+/*
+
+    Procedural macros for thinbasic crate.
+    Generated code refers to runtime support in thinbasic::core.
+
+*/
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, FnArg, ImplItem, ItemFn, ItemImpl, LitStr, Pat, ReturnType};
+
+// Name override given by #[udt(name = "...")], on the struct or on a field
+fn udt_name_attribute(attrs: &[syn::Attribute]) -> syn::Result<Option<String>> {
+    let mut name = None;
+
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("udt")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                let value: LitStr = meta.value()?.parse()?;
+                name = Some(value.value());
+                Ok(())
+            } else {
+                Err(meta.error("unsupported udt attribute, expected `name`"))
+            }
+        })?;
+    }
+
+    Ok(name)
+}
+
+fn has_repr_c(attrs: &[syn::Attribute]) -> bool {
+    let mut repr_c = false;
+
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        let _ = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("C") {
+                repr_c = true;
+            }
+            Ok(())
+        });
+    }
+
+    repr_c
+}
+
+fn expand_udt(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&input.generics, "Udt cannot be derived for generic types"));
+    }
+
+    if !has_repr_c(&input.attrs) {
+        return Err(syn::Error::new_spanned(ident, "Udt requires #[repr(C)] so the layout can be described"));
+    }
+
+    let (fields, is_union) = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => (&fields.named, false),
+            _ => return Err(syn::Error::new_spanned(ident, "Udt requires a struct with named fields")),
+        },
+        Data::Union(ref data) => (&data.fields.named, true),
+        _ => return Err(syn::Error::new_spanned(ident, "Udt can be derived only for structs and unions")),
+    };
+
+    let udt_name = udt_name_attribute(&input.attrs)?.unwrap_or_else(|| ident.to_string());
+    let (block_keyword, block_end) = if is_union {
+        ("UNION", "END UNION\n")
+    } else {
+        ("TYPE", "END TYPE\n")
+    };
+
+    let mut elements = Vec::new();
+    let mut field_checks = Vec::new();
+    let mut declarations = Vec::new();
+    let mut dependencies = Vec::new();
+
+    for field in fields {
+        let field_ident = field.ident.as_ref().unwrap();
+        let field_type = &field.ty;
+        let element_name = udt_name_attribute(&field.attrs)?.unwrap_or_else(|| field_ident.to_string());
+
+        // All union elements share the start of the union
+        let offset = if is_union {
+            quote! { 0 }
+        } else {
+            quote! { ::std::mem::offset_of!(#ident, #field_ident) }
+        };
+
+        elements.push(quote! {
+            ::thinbasic::core::udt::UdtElement {
+                name: #element_name,
+                offset: #offset,
+                size: ::std::mem::size_of::<#field_type>()
+            }
+        });
+
+        field_checks.push(quote! {
+            assert_udt_field::<#field_type>();
+        });
+
+        declarations.push(quote! {
+            code.push_str(&layout.element(#offset, ::std::mem::size_of::<#field_type>()));
+            code.push_str(&::thinbasic::core::udt::element_declaration::<#field_type>(#element_name));
+        });
+
+        dependencies.push(quote! {
+            <#field_type as ::thinbasic::core::udt::UdtField>::register_dependencies(engine);
+        });
+    }
+
+    Ok(quote! {
+        unsafe impl ::thinbasic::core::udt::UdtField for #ident {
+            fn tb_type() -> String {
+                ::thinbasic::core::module::symbol_name(#udt_name)
+            }
+
+            fn register_dependencies(engine: ::thinbasic::core::Engine) {
+                ::thinbasic::core::udt::register_udt::<#ident>(engine);
+            }
+        }
+
+        unsafe impl ::thinbasic::core::udt::Udt for #ident {
+            const NAME: &'static str = #udt_name;
+            const ELEMENTS: &'static [::thinbasic::core::udt::UdtElement] = &[#(#elements),*];
+
+            fn declaration() -> String {
+                let mut code = format!("{} {}\n", #block_keyword, ::thinbasic::core::module::symbol_name(#udt_name));
+                let mut layout = ::thinbasic::core::udt::DeclarationLayout::new(#is_union);
+                #(#declarations)*
+                code.push_str(&layout.end(::std::mem::size_of::<#ident>()));
+                code.push_str(#block_end);
+                code
+            }
+
+            #[allow(unused_variables)]
+            fn register_element_udts(engine: ::thinbasic::core::Engine) {
+                #(#dependencies)*
+            }
+        }
+
+        const _: () = {
+            fn assert_udt_field<T: ::thinbasic::core::udt::UdtField>() {}
+
+            #[allow(dead_code)]
+            fn assert_udt_fields() {
+                #(#field_checks)*
+            }
+        };
+    })
+}
+
+// Describes #[repr(C)] struct or union layout so it can be matched with thinBasic TYPE variables,
+// and generates the equivalent TYPE declaration
+#[proc_macro_derive(Udt, attributes(udt))]
+pub fn derive_udt(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand_udt(&input).unwrap_or_else(|error| error.to_compile_error()).into()
+}
+
+// Name given by attribute like #[method(name = "Start")], plain #[method] keeps the Rust name
+fn member_name_attribute(attr: &syn::Attribute) -> syn::Result<Option<String>> {
+    let mut name = None;
+
+    if let syn::Meta::List(_) = attr.meta {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                let value: LitStr = meta.value()?.parse()?;
+                name = Some(value.value());
+                Ok(())
+            } else {
+                Err(meta.error("unsupported attribute, expected `name`"))
+            }
+        })?;
+    }
+
+    Ok(name)
+}
+
+fn return_type_tokens(output: &ReturnType) -> TokenStream2 {
+    match *output {
+        ReturnType::Default => quote! { () },
+        ReturnType::Type(_, ref ty) => quote! { #ty },
+    }
+}
+
+// Constructor or method, optionally taking the engine first and then arguments parsed as Name(a, b)
+struct ClassMember {
+    takes_engine: bool,
+    parameters: Vec<(syn::Ident, syn::Type)>,
+}
+
+impl ClassMember {
+    fn new(function: &syn::ImplItemFn) -> syn::Result<ClassMember> {
+        let mut takes_engine = false;
+        let mut parameters = Vec::new();
+
+        for (index, arg) in function.sig.inputs.iter().filter_map(|arg| match *arg { FnArg::Typed(ref arg) => Some(arg), FnArg::Receiver(_) => None }).enumerate() {
+            if is_engine_type(&arg.ty) {
+                if index > 0 {
+                    return Err(syn::Error::new_spanned(&arg.ty, "engine parameter must come first"));
+                }
+                takes_engine = true;
+                continue;
+            }
+
+            let ident = match *arg.pat {
+                Pat::Ident(ref pat) => pat.ident.clone(),
+                _ => return Err(syn::Error::new_spanned(&arg.pat, "class member parameters must be plain names")),
+            };
+
+            if let Some(attribute) = arg.attrs.iter().find(|attribute| attribute.path().is_ident("default")) {
+                return Err(syn::Error::new_spanned(attribute, "class member parameters cannot have default"));
+            }
+            if wrapped_type(&arg.ty, "Option").is_some() || wrapped_type(&arg.ty, "Vec").is_some() {
+                return Err(syn::Error::new_spanned(&arg.ty, "class member parameters cannot be Option or Vec"));
+            }
+
+            parameters.push((ident, (*arg.ty).clone()));
+        }
+
+        Ok(ClassMember { takes_engine, parameters })
+    }
+
+    // Arguments are parsed before the object is borrowed, so they may use the object too
+    fn parse_steps(&self, none: &TokenStream2) -> TokenStream2 {
+        if self.parameters.is_empty() {
+            return quote! {};
+        }
+
+        let parse = self.parameters.iter().enumerate().map(|(index, (ident, ty))| {
+            let separator = if index > 0 { quote! { ::thinbasic::core::Token::Comma } } else { quote! { ::thinbasic::core::Token::OpenParens } };
+            quote! {
+                if ::thinbasic::core::expect_token(engine, #separator).is_err() {
+                    return #none;
+                }
+                let #ident = <#ty as ::thinbasic::core::ParseArg>::parse(engine);
+                if !::thinbasic::core::error_free(engine) {
+                    return #none;
+                }
+            }
+        });
+
+        quote! {
+            #(#parse)*
+            if ::thinbasic::core::expect_token(engine, ::thinbasic::core::Token::CloseParens).is_err() {
+                return #none;
+            }
+        }
+    }
+
+    fn args(&self) -> TokenStream2 {
+        let engine_arg = if self.takes_engine { quote! { engine, } } else { quote! {} };
+        let idents = self.parameters.iter().map(|(ident, _)| ident);
+
+        quote! { #engine_arg #(#idents),* }
+    }
+}
+
+// Getter takes only &self, setter &mut self and the new value
+fn check_accessor(function: &syn::ImplItemFn, is_getter: bool) -> syn::Result<()> {
+    let typed = function.sig.inputs.iter().filter(|arg| matches!(**arg, FnArg::Typed(_))).count();
+
+    match (is_getter, typed) {
+        (true, 0) | (false, 1) => Ok(()),
+        (true, _) => Err(syn::Error::new_spanned(&function.sig, "property getter takes only &self")),
+        (false, _) => Err(syn::Error::new_spanned(&function.sig, "property setter takes &mut self and the new value")),
+    }
+}
+
+#[derive(Default)]
+struct ClassProperty {
+    name: String,
+    getter: Option<(syn::Ident, TokenStream2)>,
+    setter: Option<(syn::Ident, TokenStream2)>,
+}
+
+fn expand_class(class_name: Option<String>, mut input: ItemImpl) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&input.generics, "thinbasic_class cannot be used with generic types"));
+    }
+
+    let self_ty = input.self_ty.clone();
+    let class_name = match class_name {
+        Some(name) => name,
+        None => match *self_ty {
+            syn::Type::Path(ref path) => path.path.segments.last().unwrap().ident.to_string(),
+            _ => return Err(syn::Error::new_spanned(&self_ty, "class name must be given as #[thinbasic_class(name = \"...\")]")),
+        },
+    };
+
+    let mut constructor = None;
+    let mut methods = Vec::new();
+    let mut properties: Vec<ClassProperty> = Vec::new();
+
+    for item in input.items.iter_mut() {
+        let function = match *item {
+            ImplItem::Fn(ref mut function) => function,
+            _ => continue,
+        };
+
+        let fn_ident = function.sig.ident.clone();
+        let return_type = return_type_tokens(&function.sig.output);
+
+        let mut kept_attrs = Vec::new();
+
+        for attr in function.attrs.drain(..).collect::<Vec<_>>() {
+            let path = attr.path();
+
+            if path.is_ident("constructor") {
+                constructor = Some((fn_ident.clone(), ClassMember::new(function)?));
+            } else if path.is_ident("method") {
+                let name = member_name_attribute(&attr)?.unwrap_or_else(|| fn_ident.to_string());
+                methods.push((name, fn_ident.clone(), return_type.clone(), ClassMember::new(function)?));
+            } else if path.is_ident("property_get") || path.is_ident("property_set") {
+                let is_getter = path.is_ident("property_get");
+                check_accessor(function, is_getter)?;
+                let name = member_name_attribute(&attr)?.unwrap_or_else(|| fn_ident.to_string());
+
+                let index = match properties.iter().position(|property| property.name.eq_ignore_ascii_case(&name)) {
+                    Some(index) => index,
+                    None => {
+                        properties.push(ClassProperty { name, ..Default::default() });
+                        properties.len() - 1
+                    }
+                };
+
+                if is_getter {
+                    properties[index].getter = Some((fn_ident.clone(), return_type.clone()));
+                } else {
+                    let value_type = match function.sig.inputs.iter().find(|arg| matches!(**arg, FnArg::Typed(_))) {
+                        Some(FnArg::Typed(ref arg)) => {
+                            let ty = &arg.ty;
+                            quote! { #ty }
+                        }
+                        _ => return Err(syn::Error::new_spanned(&function.sig, "property setter takes &mut self and the new value")),
+                    };
+                    properties[index].setter = Some((fn_ident.clone(), value_type));
+                }
+            } else {
+                kept_attrs.push(attr);
+            }
+        }
+
+        function.attrs = kept_attrs;
+    }
+
+    let (constructor, constructor_member) = match constructor {
+        Some(constructor) => constructor,
+        None => return Err(syn::Error::new_spanned(&self_ty, "class needs a #[constructor] function, called by NEW")),
+    };
+
+    let constructor_steps = constructor_member.parse_steps(&quote! { () });
+    let constructor_args = constructor_member.args();
+
+    let mut shims = Vec::new();
+    let mut registrations = Vec::new();
+
+    // Results go through the same conversion as keyword results, so String and Result work here too
+    for (index, (name, fn_ident, return_type, member)) in methods.iter().enumerate() {
+        let shim = format_ident!("__thinbasic_method_{}", index);
+        let output = quote! { <#return_type as ::thinbasic::core::IntoKeywordReturn>::Output };
+        let none = quote! { <#output as ::thinbasic::core::KeywordReturn>::none() };
+        let steps = member.parse_steps(&none);
+        let args = member.args();
+
+        shims.push(quote! {
+            extern "system" fn #shim(object_ptr: usize) -> #output {
+                let engine = unsafe { ::thinbasic::core::Engine::assume_script_thread() };
+
+                #steps
+
+                let result = unsafe {
+                    ::thinbasic::core::class::with_object::<#self_ty, _, _>(engine, object_ptr, |object| ::thinbasic::core::IntoKeywordReturn::into_return(object.#fn_ident(#args), engine))
+                };
+                result.unwrap_or_else(|| #none)
+            }
+        });
+
+        registrations.push(quote! {
+            ::thinbasic::core::class::add_class_method(engine, class_ptr, #name, <#output as ::thinbasic::core::KeywordReturn>::RETURN_TYPE, #shim as usize)?;
+        });
+    }
+
+    for (index, property) in properties.iter().enumerate() {
+        let shim = format_ident!("__thinbasic_property_{}", index);
+        let name = &property.name;
+
+        let return_type = match property.getter {
+            Some((_, ref return_type)) => return_type.clone(),
+            None => quote! { () },
+        };
+        let output = quote! { <#return_type as ::thinbasic::core::IntoKeywordReturn>::Output };
+        let none = quote! { <#output as ::thinbasic::core::KeywordReturn>::none() };
+
+        let get = match property.getter {
+            Some((ref getter, _)) => quote! {{
+                let result = unsafe {
+                    ::thinbasic::core::class::with_object::<#self_ty, _, _>(engine, object_ptr, |object| ::thinbasic::core::IntoKeywordReturn::into_return(object.#getter(), engine))
+                };
+                result.unwrap_or_else(|| #none)
+            }},
+            None => quote! {{
+                ::thinbasic::core::class::property_not_found(engine, #name);
+                #none
+            }},
+        };
+
+        // Setter result is converted only to raise its error, value is parsed before the object is borrowed
+        let set = match property.setter {
+            Some((ref setter, ref value_type)) => quote! {{
+                if ::thinbasic::core::class::check_property_set(engine) {
+                    let value = <#value_type as ::thinbasic::core::ParseArg>::parse(engine);
+                    if ::thinbasic::core::error_free(engine) {
+                        unsafe {
+                            ::thinbasic::core::class::with_object::<#self_ty, _, _>(engine, object_ptr, |object| {
+                                let _ = ::thinbasic::core::IntoKeywordReturn::into_return(object.#setter(value), engine);
+                            });
+                        }
+                    }
+                }
+                #none
+            }},
+            None => quote! {{
+                ::thinbasic::core::class::property_read_only(engine, #name);
+                #none
+            }},
+        };
+
+        shims.push(quote! {
+            extern "system" fn #shim(object_ptr: usize, action: i32) -> #output {
+                let engine = unsafe { ::thinbasic::core::Engine::assume_script_thread() };
+
+                match action {
+                    ::thinbasic::core::class::CLASS_ACTION_GET => #get,
+                    ::thinbasic::core::class::CLASS_ACTION_SET => #set,
+                    _ => #none
+                }
+            }
+        });
+
+        registrations.push(quote! {
+            ::thinbasic::core::class::add_class_property(engine, class_ptr, #name, <#output as ::thinbasic::core::KeywordReturn>::RETURN_TYPE, #shim as usize)?;
+        });
+    }
+
+    Ok(quote! {
+        #input
+
+        impl #self_ty {
+            // Adds the class with its methods and properties to the engine, returns engine class pointer or the first member refused
+            #[allow(dead_code)]
+            pub fn register_class(engine: ::thinbasic::core::Engine) -> Result<usize, ::thinbasic::core::RegistrationError> {
+                extern "system" fn __thinbasic_create(object_ptr: usize) {
+                    let engine = unsafe { ::thinbasic::core::Engine::assume_script_thread() };
+
+                    #constructor_steps
+
+                    unsafe {
+                        ::thinbasic::core::class::object_create::<#self_ty, _>(engine, object_ptr, <#self_ty>::#constructor(#constructor_args));
+                    }
+                }
+
+                extern "system" fn __thinbasic_destroy(object_ptr: usize) {
+                    unsafe {
+                        ::thinbasic::core::class::object_destroy::<#self_ty>(object_ptr);
+                    }
+                }
+
+                #(#shims)*
+
+                let class_ptr = ::thinbasic::core::class::add_class(engine, #class_name)?;
+                ::thinbasic::core::class::add_class_method(engine, class_ptr, "_Create", ::thinbasic::core::ReturnType::NONE, __thinbasic_create as usize)?;
+                ::thinbasic::core::class::add_class_method(engine, class_ptr, "_Destroy", ::thinbasic::core::ReturnType::NONE, __thinbasic_destroy as usize)?;
+                #(#registrations)*
+
+                Ok(class_ptr)
+            }
+        }
+    })
+}
+
+// Exposes impl block of Rust struct as thinBasic class, functions are marked with
+// #[constructor], #[method], #[property_get] and #[property_set], optionally with name = "..."
+#[proc_macro_attribute]
+pub fn thinbasic_class(args: TokenStream, input: TokenStream) -> TokenStream {
+    let mut class_name = None;
+    let args_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            let value: LitStr = meta.value()?.parse()?;
+            class_name = Some(value.value());
+            Ok(())
+        } else {
+            Err(meta.error("unsupported thinbasic_class attribute, expected `name`"))
+        }
+    });
+    parse_macro_input!(args with args_parser);
+
+    let input = parse_macro_input!(input as ItemImpl);
+
+    expand_class(class_name, input).unwrap_or_else(|error| error.to_compile_error()).into()
+}
+
+// Part of keyword grammar
+#[derive(Debug, PartialEq)]
+enum GrammarItem {
+    Parameter(String),
+    Choice(String, Vec<String>),
+    Token(String),
+}
+
+fn parse_grammar(grammar: &str) -> Result<Vec<GrammarItem>, String> {
+    let mut items = Vec::new();
+    let mut chars = grammar.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '{' {
+            chars.next();
+            let mut placeholder = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => placeholder.push(c),
+                    None => return Err(String::from("missing } in grammar")),
+                }
+            }
+
+            match placeholder.find(':') {
+                Some(colon) => {
+                    let name = placeholder[..colon].trim().to_string();
+                    let choices: Vec<String> = placeholder[colon + 1..].split('|').map(|choice| choice.trim().to_uppercase()).collect();
+                    if choices.iter().any(|choice| choice.is_empty()) {
+                        return Err(format!("empty alternative in {{{}}}", placeholder));
+                    }
+                    items.push(GrammarItem::Choice(name, choices));
+                }
+                None => items.push(GrammarItem::Parameter(placeholder.trim().to_string())),
+            }
+        } else if c.is_alphabetic() || c == '_' || c == '$' || c == '%' {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_alphanumeric() || c == '_' || c == '$' || c == '%' {
+                    word.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            items.push(GrammarItem::Token(word.to_uppercase()));
+        } else if ",()[]=;#".contains(c) {
+            chars.next();
+            items.push(GrammarItem::Token(c.to_string()));
+        } else {
+            return Err(format!("unsupported character '{}' in grammar", c));
+        }
+    }
+
+    Ok(items)
+}
+
+fn token_tokens(token: &str) -> TokenStream2 {
+    match token {
+        "," => quote! { ::thinbasic::core::Token::Comma },
+        "(" => quote! { ::thinbasic::core::Token::OpenParens },
+        ")" => quote! { ::thinbasic::core::Token::CloseParens },
+        "[" => quote! { ::thinbasic::core::Token::OpenSquareParens },
+        "]" => quote! { ::thinbasic::core::Token::CloseSquareParens },
+        "=" => quote! { ::thinbasic::core::Token::Equal },
+        ";" => quote! { ::thinbasic::core::Token::Semicolon },
+        "#" => quote! { ::thinbasic::core::Token::Hash },
+        "TO" => quote! { ::thinbasic::core::Token::To },
+        "STEP" => quote! { ::thinbasic::core::Token::Step },
+        "AS" => quote! { ::thinbasic::core::Token::As },
+        "IN" => quote! { ::thinbasic::core::Token::In },
+        "USING" => quote! { ::thinbasic::core::Token::Using },
+        keyword => quote! { ::thinbasic::core::Token::Keyword(#keyword) },
+    }
+}
+
+fn is_engine_type(ty: &syn::Type) -> bool {
+    match *ty {
+        syn::Type::Path(ref path) => path.path.segments.last().is_some_and(|segment| segment.ident == "Engine"),
+        _ => false,
+    }
+}
+
+// Type argument of Option<T> or Vec<T>
+fn wrapped_type<'a>(ty: &'a syn::Type, wrapper: &str) -> Option<&'a syn::Type> {
+    let path = match *ty {
+        syn::Type::Path(ref path) if path.qself.is_none() => path,
+        _ => return None,
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != wrapper {
+        return None;
+    }
+
+    match segment.arguments {
+        syn::PathArguments::AngleBracketed(ref arguments) if arguments.args.len() == 1 => match arguments.args[0] {
+            syn::GenericArgument::Type(ref inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+enum ParameterKind {
+    Required,
+    Optional(syn::Type),
+    Default(syn::Expr),
+    Variadic(syn::Type),
+}
+
+struct KeywordParameter {
+    ident: syn::Ident,
+    ty: syn::Type,
+    kind: ParameterKind,
+}
+
+impl KeywordParameter {
+    fn is_optional(&self) -> bool {
+        !matches!(self.kind, ParameterKind::Required)
+    }
+}
+
+// Takes #[default = ...] off the parameter, the compiler does not accept unknown attributes there
+fn take_default_attribute(arg: &mut syn::PatType) -> syn::Result<Option<syn::Expr>> {
+    let mut default = None;
+    let mut kept = Vec::new();
+
+    for attribute in arg.attrs.drain(..) {
+        if attribute.path().is_ident("default") {
+            if default.is_some() {
+                return Err(syn::Error::new_spanned(&attribute, "duplicate default"));
+            }
+            match attribute.meta {
+                syn::Meta::NameValue(ref name_value) => default = Some(name_value.value.clone()),
+                _ => return Err(syn::Error::new_spanned(&attribute, "expected #[default = value]")),
+            }
+        } else {
+            kept.push(attribute);
+        }
+    }
+
+    arg.attrs = kept;
+    Ok(default)
+}
+
+// fail_if_exists and alias = "..." given to thinbasic_keyword or thinbasic_overloads!
+#[derive(Default)]
+struct RegistrationOptions {
+    fail_if_exists: bool,
+    aliases: Vec<LitStr>,
+}
+
+impl RegistrationOptions {
+    fn is_empty(&self) -> bool {
+        !self.fail_if_exists && self.aliases.is_empty()
+    }
+
+    // Parses one option, false when the name is not a registration option
+    fn parse_option(&mut self, name: &syn::Ident, input: syn::parse::ParseStream) -> syn::Result<bool> {
+        if name == "fail_if_exists" {
+            self.fail_if_exists = true;
+        } else if name == "alias" {
+            input.parse::<syn::Token![=]>()?;
+            self.aliases.push(input.parse()?);
+        } else {
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    fn function_options(&self) -> TokenStream2 {
+        let aliases = &self.aliases;
+        let fail_if_exists = if self.fail_if_exists { quote! { .fail_if_exists() } } else { quote! {} };
+
+        quote! { ::thinbasic::core::FunctionOptions::new() #fail_if_exists #(.alias(#aliases))* }
+    }
+}
+
+fn expand_keyword(keyword_name: Option<String>, grammar: Option<LitStr>, overload: bool, options: &RegistrationOptions, mut function: ItemFn) -> syn::Result<TokenStream2> {
+    let fn_ident = function.sig.ident.clone();
+    let keyword_name = keyword_name.unwrap_or_else(|| fn_ident.to_string());
+
+    if !function.sig.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&function.sig.generics, "thinbasic_keyword cannot be used with generic functions"));
+    }
+
+    let mut takes_engine = false;
+    let mut parameters = Vec::new();
+
+    for (index, arg) in function.sig.inputs.iter_mut().enumerate() {
+        let arg = match *arg {
+            FnArg::Typed(ref mut arg) => arg,
+            FnArg::Receiver(ref receiver) => return Err(syn::Error::new_spanned(receiver, "keyword must be a free function")),
+        };
+
+        let default = take_default_attribute(arg)?;
+
+        if index == 0 && is_engine_type(&arg.ty) {
+            if let Some(default) = default {
+                return Err(syn::Error::new_spanned(default, "engine parameter cannot have default"));
+            }
+            takes_engine = true;
+            continue;
+        }
+
+        let ident = match *arg.pat {
+            Pat::Ident(ref pat) => pat.ident.clone(),
+            _ => return Err(syn::Error::new_spanned(&arg.pat, "keyword parameters must be plain names")),
+        };
+
+        let kind = match (default, wrapped_type(&arg.ty, "Option"), wrapped_type(&arg.ty, "Vec")) {
+            (Some(default), None, None) => ParameterKind::Default(default),
+            (Some(default), _, _) => return Err(syn::Error::new_spanned(default, "default cannot be used with Option or Vec parameters")),
+            (None, Some(inner), _) => ParameterKind::Optional(inner.clone()),
+            (None, None, Some(inner)) => ParameterKind::Variadic(inner.clone()),
+            (None, None, None) => ParameterKind::Required,
+        };
+
+        parameters.push(KeywordParameter { ident, ty: (*arg.ty).clone(), kind });
+    }
+
+    if overload {
+        return expand_overload(function, takes_engine, &parameters);
+    }
+
+    // Default grammar is call shaped
+    let items = match grammar {
+        Some(ref grammar) => parse_grammar(&grammar.value()).map_err(|message| syn::Error::new_spanned(grammar, message))?,
+        None => {
+            if let Some(position) = parameters.iter().position(KeywordParameter::is_optional) {
+                if let Some(required) = parameters[position..].iter().find(|parameter| !parameter.is_optional()) {
+                    return Err(syn::Error::new_spanned(&required.ident, "required parameter cannot follow optional ones"));
+                }
+            }
+
+            if let Some(position) = parameters.iter().position(|parameter| matches!(parameter.kind, ParameterKind::Variadic(_))) {
+                if position + 1 != parameters.len() {
+                    return Err(syn::Error::new_spanned(&parameters[position].ident, "Vec parameter must be the last one"));
+                }
+            }
+
+            let mut items = Vec::new();
+            if !parameters.is_empty() {
+                items.push(GrammarItem::Token(String::from("(")));
+                for (index, parameter) in parameters.iter().enumerate() {
+                    if index > 0 {
+                        items.push(GrammarItem::Token(String::from(",")));
+                    }
+                    items.push(GrammarItem::Parameter(parameter.ident.to_string()));
+                }
+                items.push(GrammarItem::Token(String::from(")")));
+            }
+            items
+        }
+    };
+
+    let error_span: TokenStream2 = match grammar {
+        Some(ref grammar) => quote! { #grammar },
+        None => quote! { #fn_ident },
+    };
+
+    // Every parameter must appear exactly once in grammar
+    for parameter in parameters.iter() {
+        let uses = items.iter().filter(|item| match **item {
+            GrammarItem::Parameter(ref name) | GrammarItem::Choice(ref name, _) => parameter.ident == name,
+            GrammarItem::Token(_) => false,
+        }).count();
+
+        if uses != 1 {
+            return Err(syn::Error::new_spanned(&error_span, format!("parameter `{}` must appear exactly once in grammar", parameter.ident)));
+        }
+    }
+
+    // Variadic parameter consumes every comma after it
+    for pair in items.windows(2) {
+        if let (GrammarItem::Parameter(name), GrammarItem::Token(token)) = (&pair[0], &pair[1]) {
+            if token == "," && parameters.iter().any(|parameter| parameter.ident == name && matches!(parameter.kind, ParameterKind::Variadic(_))) {
+                return Err(syn::Error::new_spanned(&error_span, format!("Vec parameter `{}` cannot be followed by , in grammar", name)));
+            }
+        }
+    }
+
+    let return_type = return_type_tokens(&function.sig.output);
+    let output = quote! { <#return_type as ::thinbasic::core::IntoKeywordReturn>::Output };
+    let none = quote! { <#output as ::thinbasic::core::KeywordReturn>::none() };
+
+    let find_parameter = |name: &str| -> syn::Result<&KeywordParameter> {
+        parameters.iter().find(|parameter| parameter.ident == name)
+            .ok_or_else(|| syn::Error::new_spanned(&error_span, format!("grammar uses `{}` which is not a parameter", name)))
+    };
+
+    let optional_at = |index: usize| -> bool {
+        match items.get(index) {
+            Some(GrammarItem::Parameter(name)) => parameters.iter().any(|parameter| parameter.ident == name && parameter.is_optional()),
+            _ => false,
+        }
+    };
+
+    // Set when "(" is directly followed by optional parameter, the matching ")" may be already consumed then
+    let mut pending_close = false;
+    let mut steps = Vec::new();
+    let mut index = 0;
+
+    // Keyword without parameters is called as both Name and Name()
+    if grammar.is_none() && parameters.is_empty() {
+        steps.push(quote! {
+            if ::thinbasic::core::accept_token(engine, ::thinbasic::core::Token::OpenParens)
+                && ::thinbasic::core::expect_token(engine, ::thinbasic::core::Token::CloseParens).is_err() {
+                return #none;
+            }
+        });
+    }
+
+    while index < items.len() {
+        match items[index] {
+            GrammarItem::Token(ref token) if token == "," && optional_at(index + 1) => {
+                let condition = if pending_close {
+                    quote! { !closed && ::thinbasic::core::accept_token(engine, ::thinbasic::core::Token::Comma) }
+                } else {
+                    quote! { ::thinbasic::core::accept_token(engine, ::thinbasic::core::Token::Comma) }
+                };
+                steps.push(optional_parameter_step(find_parameter(items_name(&items[index + 1]))?, condition, &none));
+                index += 2;
+                continue;
+            }
+
+            // Call shaped keyword with only optional parameters is called as Name too, as keyword without parameters
+            GrammarItem::Token(ref token) if token == "(" && optional_at(index + 1) && grammar.is_none() && parameters.iter().all(KeywordParameter::is_optional) => {
+                pending_close = true;
+                steps.push(quote! {
+                    let closed = !::thinbasic::core::accept_token(engine, ::thinbasic::core::Token::OpenParens)
+                        || ::thinbasic::core::accept_token(engine, ::thinbasic::core::Token::CloseParens);
+                });
+                steps.push(optional_parameter_step(find_parameter(items_name(&items[index + 1]))?, quote! { !closed }, &none));
+                index += 2;
+                continue;
+            }
+
+            GrammarItem::Token(ref token) if token == "(" && optional_at(index + 1) => {
+                pending_close = true;
+                steps.push(quote! {
+                    if ::thinbasic::core::expect_token(engine, ::thinbasic::core::Token::OpenParens).is_err() {
+                        return #none;
+                    }
+                    let closed = ::thinbasic::core::accept_token(engine, ::thinbasic::core::Token::CloseParens);
+                });
+                steps.push(optional_parameter_step(find_parameter(items_name(&items[index + 1]))?, quote! { !closed }, &none));
+                index += 2;
+                continue;
+            }
+
+            GrammarItem::Token(ref token) if token == ")" && pending_close => {
+                pending_close = false;
+                steps.push(quote! {
+                    if !closed && ::thinbasic::core::expect_token(engine, ::thinbasic::core::Token::CloseParens).is_err() {
+                        return #none;
+                    }
+                });
+            }
+
+            GrammarItem::Token(ref token) => {
+                let token = token_tokens(token);
+                steps.push(quote! {
+                    if ::thinbasic::core::expect_token(engine, #token).is_err() {
+                        return #none;
+                    }
+                });
+            }
+
+            GrammarItem::Parameter(ref name) => {
+                let parameter = find_parameter(name)?;
+                if parameter.is_optional() {
+                    return Err(syn::Error::new_spanned(&error_span, format!("optional parameter `{}` must follow , or ( in grammar", name)));
+                }
+                let ident = &parameter.ident;
+                let ty = &parameter.ty;
+
+                steps.push(quote! {
+                    let #ident = <#ty as ::thinbasic::core::ParseArg>::parse(engine);
+                    if !::thinbasic::core::error_free(engine) {
+                        return #none;
+                    }
+                });
+            }
+
+            GrammarItem::Choice(ref name, ref choices) => {
+                let parameter = find_parameter(name)?;
+                if parameter.is_optional() {
+                    return Err(syn::Error::new_spanned(&error_span, format!("choice parameter `{}` cannot be optional", name)));
+                }
+                let ident = &parameter.ident;
+                let ty = &parameter.ty;
+                let choices = choices.iter().map(|choice| token_tokens(choice));
+
+                steps.push(quote! {
+                    let #ident: #ty = match ::thinbasic::core::expect_choice(engine, &[#(#choices),*]) {
+                        Ok(index) => index as #ty,
+                        Err(_) => return #none
+                    };
+                });
+            }
+        }
+
+        index += 1;
+    }
+
+    let engine_arg = if takes_engine { quote! { engine, } } else { quote! {} };
+    let args = parameters.iter().map(|parameter| &parameter.ident);
+    let shim = format_ident!("__thinbasic_keyword_{}", fn_ident);
+    let register = format_ident!("register_{}", fn_ident);
+    let visibility = &function.vis;
+    let function_options = options.function_options();
+
+    Ok(quote! {
+        #function
+
+        #[allow(non_snake_case)]
+        extern "system" fn #shim() -> #output {
+            let engine = unsafe { ::thinbasic::core::Engine::assume_script_thread() };
+
+            #(#steps)*
+
+            ::thinbasic::core::IntoKeywordReturn::into_return(#fn_ident(#engine_arg #(#args),*), engine)
+        }
+
+        // Adds the keyword to the engine, returns its symbol id
+        #[allow(dead_code)]
+        #visibility fn #register(engine: ::thinbasic::core::Engine) -> Result<i32, ::thinbasic::core::RegistrationError> {
+            ::thinbasic::core::add_function_with(engine, #keyword_name, #shim, <#output as ::thinbasic::core::KeywordReturn>::RETURN_TYPE, &#function_options)
+        }
+    })
+}
+
+// Form of overloaded keyword, chosen by thinbasic_overloads! dispatcher
+fn expand_overload(function: ItemFn, takes_engine: bool, parameters: &[KeywordParameter]) -> syn::Result<TokenStream2> {
+    if let Some(parameter) = parameters.iter().find(|parameter| parameter.is_optional()) {
+        return Err(syn::Error::new_spanned(&parameter.ident, "overloaded keyword parameters cannot be optional"));
+    }
+
+    let fn_ident = &function.sig.ident;
+    let visibility = &function.vis;
+    let return_type = return_type_tokens(&function.sig.output);
+    let output = quote! { <#return_type as ::thinbasic::core::IntoKeywordReturn>::Output };
+
+    let count = parameters.len();
+    let types: Vec<&syn::Type> = parameters.iter().map(|parameter| &parameter.ty).collect();
+    let indexes: Vec<usize> = (0..count).collect();
+    let engine_arg = if takes_engine { quote! { engine, } } else { quote! {} };
+    let overload = format_ident!("__thinbasic_overload_{}", fn_ident);
+    let output_alias = format_ident!("__ThinbasicOverloadOutput_{}", fn_ident);
+
+    Ok(quote! {
+        #function
+
+        #[allow(non_camel_case_types)]
+        #visibility type #output_alias = #output;
+
+        #[allow(non_snake_case)]
+        #visibility fn #overload() -> ::thinbasic::core::keyword::KeywordOverload<#output> {
+            fn accepts(args: &[::thinbasic::core::keyword::ArgValue]) -> bool {
+                args.len() == #count #(&& <#types as ::thinbasic::core::keyword::OverloadArg>::accepts(&args[#indexes]))*
+            }
+
+            unsafe fn call(engine: ::thinbasic::core::Engine, args: &[::thinbasic::core::keyword::ArgValue]) -> #output {
+                let _ = args;
+                ::thinbasic::core::IntoKeywordReturn::into_return(#fn_ident(#engine_arg #(unsafe { <#types as ::thinbasic::core::keyword::OverloadArg>::from_arg(&args[#indexes]) }),*), engine)
+            }
+
+            fn form() -> String {
+                let types: [String; #count] = [#(<#types as ::thinbasic::core::keyword::OverloadArg>::type_name()),*];
+                format!("({})", types.join(", "))
+            }
+
+            ::thinbasic::core::keyword::KeywordOverload { accepts, call, form }
+        }
+    })
+}
+
+struct OverloadGroup {
+    register: syn::Ident,
+    keyword_name: LitStr,
+    handlers: Vec<syn::Ident>,
+    options: RegistrationOptions,
+}
+
+impl syn::parse::Parse for OverloadGroup {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let register = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        let keyword_name = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+
+        let content;
+        syn::bracketed!(content in input);
+        let handlers: syn::punctuated::Punctuated<syn::Ident, syn::Token![,]> = content.parse_terminated(syn::Ident::parse, syn::Token![,])?;
+        if handlers.is_empty() {
+            return Err(content.error("at least one overloaded keyword expected"));
+        }
+
+        let mut options = RegistrationOptions::default();
+        while input.peek(syn::Token![,]) {
+            input.parse::<syn::Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+
+            let name: syn::Ident = input.parse()?;
+            if !options.parse_option(&name, input)? {
+                return Err(syn::Error::new_spanned(name, "unsupported option, expected `fail_if_exists` or `alias`"));
+            }
+        }
+
+        Ok(OverloadGroup { register, keyword_name, handlers: handlers.into_iter().collect(), options })
+    }
+}
+
+// Registers #[thinbasic_keyword(overload)] functions under one keyword: (register_fn, "Name", [handler, ...])
+// followed by the same fail_if_exists and alias = "..." options as thinbasic_keyword
+#[proc_macro]
+pub fn thinbasic_overloads(input: TokenStream) -> TokenStream {
+    let group = parse_macro_input!(input as OverloadGroup);
+
+    let register = &group.register;
+    let keyword_name = &group.keyword_name;
+    let output = format_ident!("__ThinbasicOverloadOutput_{}", group.handlers[0]);
+    let overloads = group.handlers.iter().map(|handler| format_ident!("__thinbasic_overload_{}", handler));
+    let function_options = group.options.function_options();
+
+    quote! {
+        // Adds the overloaded keyword to the engine, returns its symbol id
+        #[allow(dead_code)]
+        pub fn #register(engine: ::thinbasic::core::Engine) -> Result<i32, ::thinbasic::core::RegistrationError> {
+            extern "system" fn dispatch() -> #output {
+                let engine = unsafe { ::thinbasic::core::Engine::assume_script_thread() };
+                ::thinbasic::core::keyword::dispatch_overload(engine, #keyword_name, &[#(#overloads()),*])
+            }
+
+            ::thinbasic::core::add_function_with(engine, #keyword_name, dispatch, <#output as ::thinbasic::core::KeywordReturn>::RETURN_TYPE, &#function_options)
+        }
+    }
+    .into()
+}
+
+fn items_name(item: &GrammarItem) -> &str {
+    match *item {
+        GrammarItem::Parameter(ref name) | GrammarItem::Choice(ref name, _) => name,
+        GrammarItem::Token(ref token) => token,
+    }
+}
+
+// Parses optional parameter when condition holds, variadic one keeps parsing while comma follows
+fn optional_parameter_step(parameter: &KeywordParameter, condition: TokenStream2, none: &TokenStream2) -> TokenStream2 {
+    let ident = &parameter.ident;
+
+    match parameter.kind {
+        ParameterKind::Optional(ref inner) => quote! {
+            let #ident = if #condition {
+                let value = <#inner as ::thinbasic::core::ParseArg>::parse(engine);
+                if !::thinbasic::core::error_free(engine) {
+                    return #none;
+                }
+                Some(value)
+            } else {
+                None
+            };
+        },
+
+        ParameterKind::Default(ref default) => {
+            let ty = &parameter.ty;
+            quote! {
+                let #ident: #ty = if #condition {
+                    let value = <#ty as ::thinbasic::core::ParseArg>::parse(engine);
+                    if !::thinbasic::core::error_free(engine) {
+                        return #none;
+                    }
+                    value
+                } else {
+                    #default
+                };
+            }
+        }
+
+        ParameterKind::Variadic(ref inner) => quote! {
+            let mut #ident = Vec::new();
+            if #condition {
+                loop {
+                    #ident.push(<#inner as ::thinbasic::core::ParseArg>::parse(engine));
+                    if !::thinbasic::core::error_free(engine) {
+                        return #none;
+                    }
+                    if !::thinbasic::core::accept_token(engine, ::thinbasic::core::Token::Comma) {
+                        break;
+                    }
+                }
+            }
+        },
+
+        ParameterKind::Required => quote! {},
+    }
+}
+
+// Registers Rust function as thinBasic keyword, optionally with name = "...", grammar = "...",
+// fail_if_exists and any number of alias = "..."
+#[proc_macro_attribute]
+pub fn thinbasic_keyword(args: TokenStream, input: TokenStream) -> TokenStream {
+    let mut keyword_name = None;
+    let mut grammar: Option<LitStr> = None;
+    let mut overload = false;
+    let mut options = RegistrationOptions::default();
+    let args_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            let value: LitStr = meta.value()?.parse()?;
+            keyword_name = Some(value.value());
+            Ok(())
+        } else if meta.path.is_ident("grammar") {
+            grammar = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("overload") {
+            overload = true;
+            Ok(())
+        } else if meta.path.is_ident("fail_if_exists") || meta.path.is_ident("alias") {
+            options.parse_option(meta.path.get_ident().unwrap(), meta.input).map(drop)
+        } else {
+            Err(meta.error("unsupported thinbasic_keyword attribute, expected `name`, `grammar`, `overload`, `fail_if_exists` or `alias`"))
+        }
+    });
+    parse_macro_input!(args with args_parser);
+
+    // Name and registration of overloaded keyword are given in thinbasic_overloads!, arguments are always call shaped
+    if overload && (keyword_name.is_some() || grammar.is_some() || !options.is_empty()) {
+        return syn::Error::new(proc_macro2::Span::call_site(), "overload cannot be combined with `name`, `grammar`, `fail_if_exists` or `alias`").to_compile_error().into();
+    }
+
+    let function = parse_macro_input!(input as ItemFn);
+
+    expand_keyword(keyword_name, grammar, overload, &options, function).unwrap_or_else(|error| error.to_compile_error()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand_class, expand_keyword, parse_grammar, GrammarItem, RegistrationOptions};
+    use syn::{parse_quote, ItemFn, ItemImpl, LitStr};
+
+    fn parameter(name: &str) -> GrammarItem {
+        GrammarItem::Parameter(String::from(name))
+    }
+
+    fn token(token: &str) -> GrammarItem {
+        GrammarItem::Token(String::from(token))
+    }
+
+    fn expand_error(grammar: Option<&str>, function: ItemFn) -> String {
+        let grammar = grammar.map(|grammar| LitStr::new(grammar, proc_macro2::Span::call_site()));
+        expand_keyword(None, grammar, false, &RegistrationOptions::default(), function).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn grammar_splits_tokens_and_parameters() {
+        let items = parse_grammar("FOR {counter} = {start} to {stop}").unwrap();
+
+        assert_eq!(items, vec![token("FOR"), parameter("counter"), token("="), parameter("start"), token("TO"), parameter("stop")]);
+    }
+
+    #[test]
+    fn grammar_reads_choices() {
+        let items = parse_grammar("({mode: read | Write}, {path})").unwrap();
+
+        assert_eq!(items, vec![
+            token("("),
+            GrammarItem::Choice(String::from("mode"), vec![String::from("READ"), String::from("WRITE")]),
+            token(","),
+            parameter("path"),
+            token(")"),
+        ]);
+    }
+
+    #[test]
+    fn grammar_reports_errors() {
+        assert_eq!(parse_grammar("({value)").unwrap_err(), "missing } in grammar");
+        assert_eq!(parse_grammar("{mode: a | }").unwrap_err(), "empty alternative in {mode: a | }");
+        assert_eq!(parse_grammar("{a} + {b}").unwrap_err(), "unsupported character '+' in grammar");
+    }
+
+    #[test]
+    fn keyword_without_parameters_accepts_parentheses() {
+        let expanded = expand_keyword(None, None, false, &RegistrationOptions::default(), parse_quote! { fn ping() -> f64 { 1.0 } }).unwrap().to_string();
+
+        assert!(expanded.contains("accept_token (engine , :: thinbasic :: core :: Token :: OpenParens)"));
+        assert!(expanded.contains("expect_token (engine , :: thinbasic :: core :: Token :: CloseParens)"));
+    }
+
+    #[test]
+    fn keyword_with_optional_parameters_only_accepts_no_parentheses() {
+        let expanded = expand_keyword(None, None, false, &RegistrationOptions::default(), parse_quote! { fn ping(count: Option<f64>) {} }).unwrap().to_string();
+
+        assert!(expanded.contains("let closed = ! :: thinbasic :: core :: accept_token (engine , :: thinbasic :: core :: Token :: OpenParens)"));
+        assert!(!expanded.contains("expect_token (engine , :: thinbasic :: core :: Token :: OpenParens)"));
+    }
+
+    #[test]
+    fn keyword_registration_passes_options() {
+        let options = RegistrationOptions { fail_if_exists: true, aliases: vec![parse_quote! { "Alias" }] };
+        let expanded = expand_keyword(None, None, false, &options, parse_quote! { fn ping() {} }).unwrap().to_string();
+
+        assert!(expanded.contains("FunctionOptions :: new () . fail_if_exists () . alias (\"Alias\")"));
+    }
+
+    #[test]
+    fn class_registration_checks_every_member() {
+        let expanded = expand_class(None, parse_quote! {
+            impl Counter {
+                #[constructor]
+                fn new() -> Self { Counter }
+
+                #[method]
+                fn value(&self) -> f64 { 0.0 }
+            }
+        }).unwrap().to_string();
+
+        assert!(expanded.contains("__thinbasic_create as usize) ?"));
+        assert!(expanded.contains("__thinbasic_destroy as usize) ?"));
+        assert!(expanded.contains("__thinbasic_method_0 as usize) ?"));
+    }
+
+    #[test]
+    fn class_property_without_setter_is_read_only() {
+        let expanded = expand_class(None, parse_quote! {
+            impl Counter {
+                #[constructor]
+                fn new() -> Self { Counter }
+
+                #[property_get]
+                fn value(&self) -> String { String::new() }
+            }
+        }).unwrap().to_string();
+
+        assert!(expanded.contains("property_read_only (engine , \"value\")"));
+        assert!(expanded.contains("IntoKeywordReturn :: into_return (object . value () , engine)"));
+    }
+
+    #[test]
+    fn class_method_parses_parameters_after_engine() {
+        let expanded = expand_class(None, parse_quote! {
+            impl Counter {
+                #[constructor]
+                fn new(start: i32) -> Self { Counter }
+
+                #[method]
+                fn add(&mut self, engine: Engine, by: i32) -> f64 { 0.0 }
+            }
+        }).unwrap().to_string();
+
+        assert!(expanded.contains("let start = < i32 as :: thinbasic :: core :: ParseArg > :: parse (engine)"));
+        assert!(expanded.contains("let by = < i32 as :: thinbasic :: core :: ParseArg > :: parse (engine)"));
+        assert!(expanded.contains("object . add (engine , by)"));
+        assert!(expanded.contains("< Counter > :: new (start)"));
+    }
+
+    #[test]
+    fn class_member_parameters_are_checked() {
+        let class_error = |input: ItemImpl| expand_class(None, input).unwrap_err().to_string();
+
+        assert_eq!(class_error(parse_quote! {
+            impl Counter {
+                #[constructor]
+                fn new(start: i32, engine: Engine) -> Self { Counter }
+            }
+        }), "engine parameter must come first");
+
+        assert_eq!(class_error(parse_quote! {
+            impl Counter {
+                #[constructor]
+                fn new() -> Self { Counter }
+
+                #[method]
+                fn add(&mut self, by: Option<i32>) {}
+            }
+        }), "class member parameters cannot be Option or Vec");
+
+        assert_eq!(class_error(parse_quote! {
+            impl Counter {
+                #[constructor]
+                fn new() -> Self { Counter }
+
+                #[property_get]
+                fn value(&self, engine: Engine) -> f64 { 0.0 }
+            }
+        }), "property getter takes only &self");
+    }
+
+    #[test]
+    fn grammar_must_name_parameters() {
+        let error = expand_error(Some("({count}, {other})"), parse_quote! { fn keyword(count: f64) {} });
+
+        assert_eq!(error, "grammar uses `other` which is not a parameter");
+    }
+
+    #[test]
+    fn parameter_must_appear_once_in_grammar() {
+        let error = expand_error(Some("({a})"), parse_quote! { fn keyword(a: f64, b: f64) {} });
+
+        assert_eq!(error, "parameter `b` must appear exactly once in grammar");
+    }
+
+    #[test]
+    fn optional_parameter_must_follow_comma_or_parens() {
+        let error = expand_error(Some("{value} TO {limit}"), parse_quote! { fn keyword(value: f64, limit: Option<f64>) {} });
+
+        assert_eq!(error, "optional parameter `limit` must follow , or ( in grammar");
+    }
+
+    #[test]
+    fn required_parameter_cannot_follow_optional() {
+        let error = expand_error(None, parse_quote! { fn keyword(a: Option<f64>, b: f64) {} });
+
+        assert_eq!(error, "required parameter cannot follow optional ones");
+    }
+
+    #[test]
+    fn vec_parameter_must_be_last() {
+        let error = expand_error(None, parse_quote! { fn keyword(values: Vec<f64>, #[default = 1.0] b: f64) {} });
+
+        assert_eq!(error, "Vec parameter must be the last one");
+    }
+
+    #[test]
+    fn vec_parameter_cannot_be_followed_by_comma() {
+        let error = expand_error(Some("({values}, {last})"), parse_quote! { fn keyword(values: Vec<f64>, last: f64) {} });
+
+        assert_eq!(error, "Vec parameter `values` cannot be followed by , in grammar");
+    }
+}